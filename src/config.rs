@@ -3,6 +3,15 @@ use std::{fs, sync::OnceLock};
 use log::info;
 use serde::Deserialize;
 
+use crate::structures::{
+    compaction_strategy::{FIFO, LEVELED, SIZE_TIERED},
+    filter_policy::{BLOCKED_BLOOM, BLOOM, XOR},
+};
+
+/*
+ * Keys added after the first release have defaults, so older configuration
+ * files keep loading. Keys which were removed since are ignored.
+ */
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub wal: WALConfig,
@@ -10,6 +19,10 @@ pub struct Config {
     pub directory: Directories,
     pub cache: CacheConfig,
     pub ss_table: SSTableConfig,
+    #[serde(default)]
+    pub blob: BlobConfig,
+    #[serde(default)]
+    pub io: IoConfig,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -21,7 +34,36 @@ pub struct MemTableConfig {
 pub struct SSTableConfig {
     pub manifest_location: String,
     pub l0_file_count_limit: usize,
-    pub l1_file_size_upper_limit: usize,
+    #[serde(default = "default_l0_stop_writes_trigger")]
+    pub l0_stop_writes_trigger: usize,
+    #[serde(default = "default_max_levels")]
+    pub max_levels: usize,
+    #[serde(default = "default_max_bytes_for_level_base")]
+    pub max_bytes_for_level_base: u64,
+    #[serde(default = "default_level_size_multiplier")]
+    pub level_size_multiplier: u64,
+    #[serde(default = "default_target_file_size")]
+    pub target_file_size: u64,
+    #[serde(default = "default_compaction_style")]
+    pub compaction_style: String,
+    #[serde(default = "default_min_merge_width")]
+    pub min_merge_width: usize,
+    #[serde(default = "default_fifo_max_size")]
+    pub fifo_max_size: u64,
+    #[serde(default)]
+    pub fifo_ttl_seconds: u64,
+    #[serde(default = "default_max_background_compactions")]
+    pub max_background_compactions: usize,
+    #[serde(default = "default_max_subcompactions")]
+    pub max_subcompactions: usize,
+    #[serde(default = "default_index_partition_size")]
+    pub index_partition_size: usize,
+    #[serde(default)]
+    pub mmap_reads: bool,
+    #[serde(default = "default_range_filter_prefix_len")]
+    pub range_filter_prefix_len: usize,
+    #[serde(default = "default_max_open_files")]
+    pub max_open_files: usize,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -32,7 +74,42 @@ pub struct WALConfig {
 #[derive(Debug, Deserialize, Clone)]
 pub struct CacheConfig {
     pub index_size: usize,
-    pub bloom_filter_size: usize,
+    #[serde(default = "default_bloom_filter_false_positive_rate")]
+    pub bloom_filter_false_positive_rate: f64,
+    #[serde(default = "default_filter_policy")]
+    pub filter_policy: String,
+    #[serde(default = "default_block_cache_size")]
+    pub block_cache_size: usize,
+    #[serde(default = "default_block_cache_shards")]
+    pub block_cache_shards: usize,
+    #[serde(default = "default_pin_index_and_filter_blocks")]
+    pub pin_index_and_filter_blocks: bool,
+    #[serde(default = "default_high_priority_pool_ratio")]
+    pub high_priority_pool_ratio: f64,
+    #[serde(default = "default_row_cache_size")]
+    pub row_cache_size: usize,
+    #[serde(default)]
+    pub secondary_cache_path: String,
+    #[serde(default = "default_secondary_cache_size")]
+    pub secondary_cache_size: usize,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct BlobConfig {
+    #[serde(default = "default_value_size_threshold")]
+    pub value_size_threshold: usize,
+    #[serde(default = "default_gc_garbage_ratio")]
+    pub gc_garbage_ratio: f64,
+}
+
+#[derive(Debug, Default, Deserialize, Clone)]
+pub struct IoConfig {
+    #[serde(default)]
+    pub direct_writes: bool,
+    #[serde(default)]
+    pub direct_compaction_reads: bool,
+    #[serde(default)]
+    pub rate_limit_bytes_per_second: u64,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -40,11 +117,140 @@ pub struct Directories {
     pub log: String,
     pub wal: String,
     pub ss_table: String,
+    #[serde(default = "default_blob_directory")]
+    pub blob: String,
 }
 
 pub static CONFIG: OnceLock<Config> = OnceLock::new();
 
+fn default_l0_stop_writes_trigger() -> usize {
+    20
+}
+
+fn default_max_levels() -> usize {
+    7
+}
+
+fn default_max_bytes_for_level_base() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_level_size_multiplier() -> u64 {
+    10
+}
+
+fn default_target_file_size() -> u64 {
+    2 * 1024 * 1024
+}
+
+fn default_compaction_style() -> String {
+    LEVELED.to_owned()
+}
+
+fn default_min_merge_width() -> usize {
+    4
+}
+
+fn default_fifo_max_size() -> u64 {
+    1024 * 1024 * 1024
+}
+
+fn default_max_background_compactions() -> usize {
+    4
+}
+
+fn default_max_subcompactions() -> usize {
+    4
+}
+
+fn default_index_partition_size() -> usize {
+    2
+}
+
+fn default_range_filter_prefix_len() -> usize {
+    8
+}
+
+fn default_max_open_files() -> usize {
+    64
+}
+
+fn default_bloom_filter_false_positive_rate() -> f64 {
+    0.01
+}
+
+fn default_filter_policy() -> String {
+    BLOOM.to_owned()
+}
+
+fn default_block_cache_size() -> usize {
+    8 * 1024 * 1024
+}
+
+fn default_block_cache_shards() -> usize {
+    16
+}
+
+fn default_pin_index_and_filter_blocks() -> bool {
+    true
+}
+
+fn default_high_priority_pool_ratio() -> f64 {
+    0.5
+}
+
+fn default_row_cache_size() -> usize {
+    1024 * 1024
+}
+
+fn default_secondary_cache_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_value_size_threshold() -> usize {
+    64
+}
+
+fn default_gc_garbage_ratio() -> f64 {
+    0.5
+}
+
+fn default_blob_directory() -> String {
+    "data/blobs".to_owned()
+}
+
+impl Default for BlobConfig {
+    fn default() -> Self {
+        Self {
+            value_size_threshold: default_value_size_threshold(),
+            gc_garbage_ratio: default_gc_garbage_ratio(),
+        }
+    }
+}
+
 impl Config {
+    /*
+     * Names are checked here, a typo fails on startup instead of on the
+     * first flush or compaction
+     */
+    pub fn parse(content: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let config: Config = toml::from_str(content)?;
+
+        if ![LEVELED, SIZE_TIERED, FIFO].contains(&config.ss_table.compaction_style.as_str()) {
+            return Err(format!(
+                "unknown compaction style {}",
+                config.ss_table.compaction_style
+            )
+            .into());
+        }
+
+        if ![BLOOM, BLOCKED_BLOOM, XOR].contains(&config.cache.filter_policy.as_str()) {
+            return Err(format!("unknown filter policy {}", config.cache.filter_policy).into());
+        }
+
+        Ok(config)
+    }
+
     pub fn load() -> Result<Self, Box<dyn std::error::Error>> {
         let env = std::env::var("ENV").unwrap_or_else(|_| "dev".to_owned());
 
@@ -53,10 +259,11 @@ impl Config {
         let file_name = format!("config.{}.toml", env);
 
         let content = fs::read_to_string(file_name)?;
-        let config: Config = toml::from_str(&content)?;
+        let config = Config::parse(&content)?;
 
         let _ = fs::create_dir_all(&config.directory.wal);
         let _ = fs::create_dir_all(&config.directory.ss_table);
+        let _ = fs::create_dir_all(&config.directory.blob);
 
         Ok(config)
     }
@@ -66,10 +273,11 @@ impl Config {
         let file_name = "config.test.toml".to_owned();
 
         let content = fs::read_to_string(file_name)?;
-        let config: Config = toml::from_str(&content)?;
+        let config = Config::parse(&content)?;
 
         let _ = fs::create_dir_all(&config.directory.wal);
         let _ = fs::create_dir_all(&config.directory.ss_table);
+        let _ = fs::create_dir_all(&config.directory.blob);
 
         Ok(config)
     }
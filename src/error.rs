@@ -8,6 +8,9 @@ pub enum LsmError {
     #[error("SSTable operation failed: {0}")]
     SsTable(String),
 
+    #[error("Blob operation failed: {0}")]
+    Blob(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
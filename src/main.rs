@@ -1,18 +1,18 @@
 use std::io;
 
-use crate::{config::Config, error::Result, structures::lsm::Lsm};
 use log::info;
-
-mod config;
-mod error;
-mod structures;
+use lsm_tree::{
+    config::Config,
+    error::{LsmError, Result},
+    structures::lsm::Lsm,
+};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let config = Config::global();
 
     log4rs::init_file(&config.directory.log, Default::default())
-        .map_err(|e| crate::error::LsmError::Log(e.to_string()))?;
+        .map_err(|e| LsmError::Log(e.to_string()))?;
     info!("application is starting");
 
     let mut lsm = Lsm::default();
@@ -33,21 +33,67 @@ async fn main() -> Result<()> {
                 println!("  delete - delete value from a tree");
                 println!("  get - get value based on key");
                 println!("  print - print tree");
+                println!("  scan - print every value with a key from start to end");
+                println!("  compact - compact every ss_table from start to end into a level");
+                println!("  rate - limit background I/O to bytes per second, 0 is no limit");
+                println!("  properties - print properties of every ss_table");
+                println!("  stats - print block cache and compaction statistics and level scores");
                 println!("  exit - exit the program");
             }
             "get" => {
                 let key = get_input("Enter a key: ");
                 match lsm.get(&key) {
-                    None => println!("Key is not present!"),
-                    Some(node) => {
+                    Ok(None) => println!("Key is not present!"),
+                    Ok(Some(node)) => {
                         println!("{:?}", node)
                     }
+                    Err(e) => println!("Unable to read the key: {}", e),
+                }
+            }
+            "scan" => {
+                let start = get_input("Enter a start key: ");
+                let end = get_input("Enter an end key (exclusive): ");
+                match lsm.scan(&start, &end) {
+                    Ok(entries) => entries
+                        .iter()
+                        .for_each(|(key, value)| println!("{}: {:?}", key, value)),
+                    Err(e) => println!("Unable to scan the range: {}", e),
+                }
+            }
+            "compact" => {
+                let start = get_input("Enter a start key: ");
+                let end = get_input("Enter an end key: ");
+                let level = get_input("Enter a target level: ");
+                match level.parse() {
+                    Ok(level) => match lsm.compact_range(&start, &end, level) {
+                        Ok(()) => println!("Compaction done"),
+                        Err(e) => println!("Unable to compact the range: {}", e),
+                    },
+                    Err(e) => println!("Invalid level: {}", e),
+                }
+            }
+            "rate" => {
+                let rate = get_input("Enter bytes per second: ");
+                match rate.parse() {
+                    Ok(rate) => lsm.set_io_rate_limit(rate),
+                    Err(e) => println!("Invalid rate: {}", e),
                 }
             }
             "delete" => {
                 let key = get_input("Enter a key: ");
                 let _ = lsm.delete(&key);
             }
+            "properties" => match lsm.table_properties() {
+                Ok(tables) => tables
+                    .iter()
+                    .for_each(|(table, properties)| println!("{}: {:?}", table.path, properties)),
+                Err(e) => println!("Unable to read table properties: {}", e),
+            },
+            "stats" => {
+                println!("{:?}", lsm.block_cache_stats());
+                println!("{:?}", lsm.compaction_stats());
+                println!("{:?}", lsm.compaction_scores());
+            }
             "exit" => break,
             _ => continue,
         };
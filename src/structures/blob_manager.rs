@@ -0,0 +1,161 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::sync::{Arc, RwLock};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::{LsmError, Result};
+use crate::structures::manifest::{BlobFileInfo, Manifest};
+use crate::structures::ss_table_manager::SSTableValue;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlobPointer {
+    pub file_id: usize,
+    pub offset: u64,
+    pub size: u64,
+}
+
+#[derive(Debug)]
+pub struct BlobManager {}
+
+/*
+ * Appends values above the configured threshold to a single blob file.
+ * The file is only created once the first large value shows up.
+ */
+#[derive(Debug)]
+pub struct BlobWriter {
+    manifest: Arc<RwLock<Manifest>>,
+    file: Option<(File, BlobFileInfo)>,
+}
+
+pub fn blob_file_path(id: usize) -> String {
+    format!("{}/{:010}.blob", Config::global().directory.blob, id)
+}
+
+impl BlobManager {
+    pub fn read(pointer: &BlobPointer) -> Result<String> {
+        info!(
+            "Reading blob from file {} offset {} and size {}",
+            pointer.file_id, pointer.offset, pointer.size
+        );
+
+        let mut file = File::open(blob_file_path(pointer.file_id))?;
+        file.seek(SeekFrom::Start(pointer.offset))?;
+
+        let mut buffer = vec![0u8; pointer.size as usize];
+        file.read_exact(&mut buffer)?;
+
+        String::from_utf8(buffer).map_err(|e| LsmError::Blob(e.to_string()))
+    }
+
+    /*
+     * None is a thombstone, a blob which can't be read is an error
+     */
+    pub fn resolve(value: Option<&SSTableValue>) -> Result<Option<String>> {
+        match value {
+            None => Ok(None),
+            Some(SSTableValue::Inline(value)) => Ok(Some(value.clone())),
+            Some(SSTableValue::Blob(pointer)) => BlobManager::read(pointer).map(Some),
+        }
+    }
+}
+
+impl BlobWriter {
+    pub fn new(manifest: Arc<RwLock<Manifest>>) -> Self {
+        Self {
+            manifest,
+            file: None,
+        }
+    }
+
+    pub fn separate(&mut self, value: &str) -> Result<SSTableValue> {
+        if value.len() < Config::global().blob.value_size_threshold {
+            return Ok(SSTableValue::Inline(value.to_owned()));
+        }
+
+        Ok(SSTableValue::Blob(self.append(value)?))
+    }
+
+    fn append(&mut self, value: &str) -> Result<BlobPointer> {
+        if self.file.is_none() {
+            let (path, id) = self
+                .manifest
+                .write()
+                .expect("unable to open manifest for writes")
+                .create_blob_filename();
+
+            info!("creating blob file {}", path);
+
+            let file = OpenOptions::new()
+                .read(true)
+                .append(true)
+                .create(true)
+                .open(&path)?;
+
+            self.file = Some((file, BlobFileInfo::new(id, path)));
+        }
+
+        let (file, info) = self.file.as_mut().expect("blob file is open");
+
+        let pointer = BlobPointer {
+            file_id: info.id,
+            offset: info.total_bytes,
+            size: value.len() as u64,
+        };
+
+        file.write_all(value.as_bytes())?;
+        info.total_bytes += pointer.size;
+
+        Ok(pointer)
+    }
+
+    /*
+     * Registers the blob file in the manifest, has to happen before the
+     * ss_table pointing into it is added.
+     */
+    pub fn finish(self) -> Result<()> {
+        let manifest = Arc::clone(&self.manifest);
+        let Some(info) = self.close()? else {
+            return Ok(());
+        };
+
+        manifest
+            .write()
+            .expect("unable to open manifest for writes")
+            .add_blob_file(info);
+
+        Ok(())
+    }
+
+    /*
+     * Syncs the blob file without registering it, the caller adds it to the
+     * manifest together with the ss_tables pointing into it
+     */
+    pub fn close(self) -> Result<Option<BlobFileInfo>> {
+        let Some((file, info)) = self.file else {
+            return Ok(None);
+        };
+
+        if let Err(e) = file.sync_all() {
+            let _ = fs::remove_file(&info.path);
+            return Err(e.into());
+        }
+
+        Ok(Some(info))
+    }
+
+    /*
+     * Deletes the blob file of values which are not going to be referenced
+     */
+    pub fn abandon(self) {
+        if let Some((file, info)) = self.file {
+            info!("abandoning the blob file {}", info.path);
+
+            drop(file);
+            let _ = fs::remove_file(&info.path);
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use log::{error, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::structures::cache::Partition;
+use crate::structures::secondary_cache::SecondaryCache;
+use crate::structures::ss_table_manager::DataBlock;
+
+/*
+ * Shards smaller than this would not fit most blocks, the cache gets fewer
+ * shards instead
+ */
+pub const MIN_SHARD_CAPACITY: usize = 4096;
+
+/*
+ * Blocks are keyed by the id of the ss_table and the offset of the block
+ * inside of it
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct BlockKey {
+    pub file_id: u64,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum Block {
+    Data(Arc<DataBlock>),
+    Partition(Arc<Partition>),
+}
+
+/*
+ * High priority blocks are only evicted once no low priority block is left
+ * in the shard. They may take up to the high priority pool ratio of the
+ * shard, beyond that the least recently used ones are treated as low
+ * priority, so pinned blocks never push every data block out.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub secondary_hits: u64,
+    pub usage: usize,
+    pub capacity: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    block: Block,
+    charge: usize,
+    priority: Priority,
+    last_used: u64,
+}
+
+/*
+ * Least recently used blocks of one shard, the lru lists map the tick of
+ * the last use to the key, one list per priority
+ */
+#[derive(Debug, Default)]
+struct Shard {
+    entries: BTreeMap<BlockKey, Entry>,
+    low: BTreeMap<u64, BlockKey>,
+    high: BTreeMap<u64, BlockKey>,
+    usage: usize,
+    capacity: usize,
+    high_usage: usize,
+    high_capacity: usize,
+    tick: u64,
+}
+
+/*
+ * Byte bounded block cache split into shards, each behind its own lock, so
+ * readers of different blocks rarely wait on each other. Evicted data blocks
+ * spill into the secondary cache if there is one, index and filter blocks
+ * are cheap to read again and are dropped.
+ */
+#[derive(Debug)]
+pub struct BlockCache {
+    shards: Vec<Mutex<Shard>>,
+    capacity: usize,
+    secondary: Option<SecondaryCache>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    secondary_hits: AtomicU64,
+}
+
+impl Shard {
+    fn lru(&mut self, priority: Priority) -> &mut BTreeMap<u64, BlockKey> {
+        match priority {
+            Priority::Low => &mut self.low,
+            Priority::High => &mut self.high,
+        }
+    }
+
+    fn get(&mut self, key: &BlockKey) -> Option<Block> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let entry = self.entries.get_mut(key)?;
+        let (last_used, priority) = (entry.last_used, entry.priority);
+        entry.last_used = tick;
+        let block = entry.block.clone();
+
+        let lru = self.lru(priority);
+        lru.remove(&last_used);
+        lru.insert(tick, *key);
+
+        Some(block)
+    }
+
+    /*
+     * Returns the evicted blocks
+     */
+    fn insert(
+        &mut self,
+        key: BlockKey,
+        block: Block,
+        charge: usize,
+        priority: Priority,
+    ) -> Vec<(BlockKey, Block)> {
+        self.remove(&key);
+        let mut evicted = Vec::new();
+
+        self.tick += 1;
+        let tick = self.tick;
+
+        self.entries.insert(
+            key,
+            Entry {
+                block,
+                charge,
+                priority,
+                last_used: tick,
+            },
+        );
+        self.lru(priority).insert(tick, key);
+        self.usage += charge;
+        if priority == Priority::High {
+            self.high_usage += charge;
+        }
+
+        while self.high_usage > self.high_capacity {
+            let Some((last_used, oldest)) = self.high.pop_first() else {
+                break;
+            };
+
+            trace!("moving block {:?} out of the high priority pool", oldest);
+            self.low.insert(last_used, oldest);
+            if let Some(entry) = self.entries.get_mut(&oldest) {
+                entry.priority = Priority::Low;
+                self.high_usage -= entry.charge;
+            }
+        }
+
+        while self.usage > self.capacity {
+            let Some((_, oldest)) = self.low.first_key_value().or(self.high.first_key_value())
+            else {
+                break;
+            };
+
+            let oldest = *oldest;
+            trace!("evicting block {:?}", oldest);
+            if let Some(entry) = self.remove(&oldest) {
+                evicted.push((oldest, entry.block));
+            }
+        }
+
+        evicted
+    }
+
+    fn remove(&mut self, key: &BlockKey) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru(entry.priority).remove(&entry.last_used);
+        self.usage -= entry.charge;
+        if entry.priority == Priority::High {
+            self.high_usage -= entry.charge;
+        }
+
+        Some(entry)
+    }
+
+    fn remove_file(&mut self, file_id: u64) {
+        let keys = self
+            .entries
+            .keys()
+            .filter(|key| key.file_id == file_id)
+            .copied()
+            .collect::<Vec<BlockKey>>();
+
+        keys.iter().for_each(|key| {
+            self.remove(key);
+        });
+    }
+}
+
+/*
+ * An unusable secondary cache is logged and left out, the block cache works without it
+ */
+impl Default for BlockCache {
+    fn default() -> Self {
+        let config = &Config::global().cache;
+        let block_cache = BlockCache::new(config.block_cache_size, config.block_cache_shards)
+            .with_high_priority_pool_ratio(config.high_priority_pool_ratio);
+
+        if config.secondary_cache_path.is_empty() {
+            return block_cache;
+        }
+
+        match SecondaryCache::open(&config.secondary_cache_path, config.secondary_cache_size) {
+            Ok(secondary) => block_cache.with_secondary(secondary),
+            Err(e) => {
+                error!(
+                    "Unable to open the secondary cache {}: {}",
+                    config.secondary_cache_path, e
+                );
+                block_cache
+            }
+        }
+    }
+}
+
+impl BlockCache {
+    /*
+     * High priority blocks may take the whole shard until a ratio is set
+     */
+    pub fn new(capacity: usize, shards: usize) -> Self {
+        let shards = shards.min(capacity / MIN_SHARD_CAPACITY).max(1);
+
+        Self {
+            shards: (0..shards)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        capacity: capacity / shards,
+                        high_capacity: capacity / shards,
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+            capacity,
+            secondary: None,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+            secondary_hits: AtomicU64::new(0),
+        }
+    }
+
+    pub fn with_high_priority_pool_ratio(self, ratio: f64) -> Self {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            shard.high_capacity = (shard.capacity as f64 * ratio.clamp(0.0, 1.0)) as usize;
+        }
+
+        self
+    }
+
+    pub fn with_secondary(mut self, secondary: SecondaryCache) -> Self {
+        self.secondary = Some(secondary);
+        self
+    }
+
+    fn shard(&self, key: &BlockKey) -> &Mutex<Shard> {
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&key.file_id.to_le_bytes());
+        bytes[8..].copy_from_slice(&key.offset.to_le_bytes());
+        let hash = xxhash_rust::xxh3::xxh3_64(&bytes);
+
+        &self.shards[(hash % self.shards.len() as u64) as usize]
+    }
+
+    pub fn get(&self, key: &BlockKey) -> Option<Block> {
+        if let Some(block) = self.shard(key).lock().unwrap().get(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Some(block);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        self.get_secondary(key)
+    }
+
+    /*
+     * Blocks found in the secondary cache move back into memory
+     */
+    fn get_secondary(&self, key: &BlockKey) -> Option<Block> {
+        let bytes = self.secondary.as_ref()?.get(key)?;
+        let entries: DataBlock = bincode::deserialize(&bytes)
+            .inspect_err(|e| error!("Unable to decode secondary cache block {:?}: {}", key, e))
+            .ok()?;
+
+        self.secondary_hits.fetch_add(1, Ordering::Relaxed);
+
+        let block = Block::Data(Arc::new(entries));
+        self.insert(*key, block.clone(), bytes.len(), Priority::Low);
+
+        Some(block)
+    }
+
+    /*
+     * Blocks larger than a whole shard are not cached at all
+     */
+    pub fn insert(&self, key: BlockKey, block: Block, charge: usize, priority: Priority) {
+        let mut shard = self.shard(&key).lock().unwrap();
+
+        if charge > shard.capacity {
+            return;
+        }
+
+        let evicted = shard.insert(key, block, charge, priority);
+        drop(shard);
+
+        let Some(secondary) = self.secondary.as_ref() else {
+            return;
+        };
+
+        for (key, block) in evicted {
+            if let Block::Data(entries) = block {
+                match bincode::serialize(entries.as_ref()) {
+                    Ok(bytes) => secondary.insert(key, bytes),
+                    Err(e) => error!("Unable to encode block {:?}: {}", key, e),
+                }
+            }
+        }
+    }
+
+    pub fn remove_file(&self, file_id: u64) {
+        self.shards
+            .iter()
+            .for_each(|shard| shard.lock().unwrap().remove_file(file_id));
+
+        if let Some(secondary) = self.secondary.as_ref() {
+            secondary.remove_file(file_id);
+        }
+    }
+
+    pub fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            secondary_hits: self.secondary_hits.load(Ordering::Relaxed),
+            usage: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().usage)
+                .sum(),
+            capacity: self.capacity,
+        }
+    }
+}
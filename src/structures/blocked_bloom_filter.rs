@@ -0,0 +1,67 @@
+use std::f64::consts::LN_2;
+
+use serde::{Deserialize, Serialize};
+
+const BLOCK_BITS: usize = 512;
+
+/*
+ * Bloom filter split into 64 byte blocks, one cache line each. A key hashes
+ * into a single block and all of its probes stay inside of it, so a lookup
+ * touches one cache line, in exchange for a slightly higher false positive
+ * rate than a standard bloom filter of the same size.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BlockedBloomFilter {
+    blocks: Vec<[u64; 8]>,
+    num_probes: usize,
+}
+
+impl BlockedBloomFilter {
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        let keys = expected_keys.max(1) as f64;
+
+        let num_bits = (-keys * false_positive_rate.ln() / (LN_2 * LN_2)).ceil() as usize;
+        let num_probes = (num_bits as f64 / keys * LN_2).round().clamp(1.0, 16.0) as usize;
+
+        Self {
+            blocks: vec![[0; 8]; num_bits.div_ceil(BLOCK_BITS).max(1)],
+            num_probes,
+        }
+    }
+
+    pub fn from_keys(keys: &[&str], false_positive_rate: f64) -> Self {
+        let mut result = Self::new(keys.len(), false_positive_rate);
+        keys.iter().for_each(|key| result.update(key));
+        result
+    }
+
+    /*
+     * The lower half of the hash picks the block, the upper half is used for
+     * double hashing the probes inside of the block
+     */
+    fn probes(&self, value: &str) -> (usize, impl Iterator<Item = usize> + use<>) {
+        let hash = xxhash_rust::xxh3::xxh3_64(value.as_bytes());
+
+        let block = ((hash as u32 as u64 * self.blocks.len() as u64) >> 32) as usize;
+        let h1 = (hash >> 32) as u16 as usize;
+        let h2 = (hash >> 48) as usize | 1;
+
+        let bits = (0..self.num_probes).map(move |probe| (h1 + probe * h2) % BLOCK_BITS);
+
+        (block, bits)
+    }
+
+    pub fn update(&mut self, value: &str) {
+        let (block, bits) = self.probes(value);
+
+        for bit in bits {
+            self.blocks[block][bit / 64] |= 1 << (bit % 64);
+        }
+    }
+
+    pub fn contains(&self, value: &str) -> bool {
+        let (block, mut bits) = self.probes(value);
+
+        bits.all(|bit| self.blocks[block][bit / 64] & (1 << (bit % 64)) != 0)
+    }
+}
@@ -1,46 +1,101 @@
+use std::f64::consts::LN_2;
+
 use log::info;
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::error::{LsmError, Result};
 
+/*
+ * Bloom filter stored as a bitset. Every probe uses its own xxh3 seed,
+ * the same scheme the legacy filters used with their three probes, so
+ * legacy filters convert into this layout without rehashing any key.
+ */
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: usize,
+    num_probes: usize,
+}
+
+/*
+ * Layout of the filters written before format version 4, one usize per bit
+ */
+#[derive(Deserialize)]
+struct LegacyBloomFilter {
     value: Vec<usize>,
     size: usize,
 }
 
-impl Default for BloomFilter {
-    fn default() -> Self {
-        let size = Config::global().cache.bloom_filter_size;
+impl BloomFilter {
+    /*
+     * Sizes the filter for the expected number of keys, with the optimal
+     * number of probes for the resulting bits per key
+     */
+    pub fn new(expected_keys: usize, false_positive_rate: f64) -> Self {
+        let keys = expected_keys.max(1) as f64;
+
+        let num_bits = (-keys * false_positive_rate.ln() / (LN_2 * LN_2))
+            .ceil()
+            .max(64.0) as usize;
+        let num_probes = (num_bits as f64 / keys * LN_2).round().clamp(1.0, 30.0) as usize;
 
         Self {
-            value: vec![0; size],
-            size,
+            bits: vec![0; num_bits.div_ceil(64)],
+            num_bits,
+            num_probes,
         }
     }
-}
 
-impl BloomFilter {
+    pub fn from_keys(keys: &[&str], false_positive_rate: f64) -> Self {
+        let mut result = Self::new(keys.len(), false_positive_rate);
+        keys.iter().for_each(|key| result.update(key));
+        result
+    }
+
+    pub fn decode(bytes: &[u8], legacy: bool) -> Result<Self> {
+        if !legacy {
+            return bincode::deserialize(bytes).map_err(|e| LsmError::SsTable(e.to_string()));
+        }
+
+        let legacy: LegacyBloomFilter =
+            bincode::deserialize(bytes).map_err(|e| LsmError::SsTable(e.to_string()))?;
+
+        let mut bits = vec![0u64; legacy.size.div_ceil(64)];
+        legacy
+            .value
+            .iter()
+            .enumerate()
+            .filter(|(_, bit)| **bit == 1)
+            .for_each(|(index, _)| bits[index / 64] |= 1 << (index % 64));
+
+        Ok(Self {
+            bits,
+            num_bits: legacy.size,
+            num_probes: 3,
+        })
+    }
+
+    fn probe(&self, value: &str, probe: usize) -> usize {
+        xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), probe as u64 + 1) as usize
+            % self.num_bits
+    }
+
     pub fn update(&mut self, value: &str) {
         info!("updating the bloomfilter with key {}", value);
 
-        let val1 = xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), 1) as usize % self.size;
-        let val2 = xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), 2) as usize % self.size;
-        let val3 = xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), 3) as usize % self.size;
-
-        self.value[val1] = 1;
-        self.value[val2] = 1;
-        self.value[val3] = 1;
+        for probe in 0..self.num_probes {
+            let bit = self.probe(value, probe);
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
     }
 
     pub fn contains(&self, value: &str) -> bool {
         info!("searching the bloomfilter for key {}", value);
 
-        let val1 = xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), 1) as usize % self.size;
-        let val2 = xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), 2) as usize % self.size;
-        let val3 = xxhash_rust::xxh3::xxh3_64_with_seed(value.as_bytes(), 3) as usize % self.size;
-
-        let res = self.value[val1] == 1 && self.value[val2] == 1 && self.value[val3] == 1;
+        let res = (0..self.num_probes).all(|probe| {
+            let bit = self.probe(value, probe);
+            self.bits[bit / 64] & (1 << (bit % 64)) != 0
+        });
         info!("{}", res);
         res
     }
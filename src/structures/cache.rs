@@ -1,22 +1,46 @@
 use core::str;
 use std::collections::BTreeMap;
-use std::fs::{self, File};
-use std::io::{Read, Seek, SeekFrom};
+use std::sync::{Arc, Mutex};
 
 use log::info;
 use serde::{Deserialize, Serialize};
 
 use crate::config::Config;
-use crate::structures::bloom_filter::BloomFilter;
-use crate::structures::ss_table_manager::SSTableFooter;
+use crate::error::{LsmError, Result};
+use crate::structures::block_cache::{Block, BlockCache, BlockCacheStats, BlockKey, Priority};
+use crate::structures::filter_policy::KeyFilter;
+use crate::structures::mmap_reader::MmapReader;
+use crate::structures::range_filter::RangeFilter;
+use crate::structures::rate_limiter::RateLimiter;
+use crate::structures::row_cache::RowCache;
+use crate::structures::ss_table_manager::{BlockHandle, DataBlock, SSTableManager};
+use crate::structures::table_cache::{TableCache, table_id};
 
+/*
+ * Metadata of a file is only loaded the first time the file is read, and
+ * only files listed in the manifest are ever asked for
+ */
 #[derive(Debug, Default)]
 pub struct Cache {
-    pub bloom_filters: BTreeMap<String, BloomFilter>,
-    pub indexes: BTreeMap<String, Vec<IndexRecord>>,
+    tables: Mutex<BTreeMap<String, Arc<CachedTable>>>,
+    block_cache: BlockCache,
+    row_cache: RowCache,
+    table_cache: TableCache,
+    mmap_reader: MmapReader,
+    rate_limiter: Arc<RateLimiter>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+/*
+ * Blocks are cached under the id of the ss_table, ids are never reused, so
+ * blocks of a removed file are never served for a later file
+ */
+#[derive(Debug)]
+struct CachedTable {
+    id: u64,
+    top_level_index: TopLevelIndex,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct IndexRecord {
     pub start: String,
     pub end: String,
@@ -24,136 +48,289 @@ pub struct IndexRecord {
     pub size: u64,
 }
 
+/*
+ * Entry of the top level index, points to the index and the filter
+ * partition covering the keys from start to end
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexPartition {
+    pub start: String,
+    pub end: String,
+    pub index: BlockHandle,
+    pub filter: BlockHandle,
+}
+
+/*
+ * The format version and filter policy of the file decide how its data blocks
+ * and filter partitions are decoded, the range filter is only there if the
+ * file was written with one
+ */
+#[derive(Debug, Clone)]
+pub struct TopLevelIndex {
+    pub version: u64,
+    pub filter_policy: String,
+    pub range_filter: Option<RangeFilter>,
+    pub partitions: Vec<IndexPartition>,
+}
+
+#[derive(Debug)]
+pub struct Partition {
+    pub index: Vec<IndexRecord>,
+    pub filter: Box<dyn KeyFilter>,
+}
+
 impl Cache {
     pub fn new() -> Self {
-        let mut res = Self::default();
-        res.read_on_startup();
-        res
+        Self::default()
     }
 
-    pub fn read_on_startup(&mut self) {
-        let file_path = Config::global().directory.ss_table.as_str();
-
-        let mut entries = fs::read_dir(file_path)
+    pub fn add(&mut self, id: usize, file_name: &str, top_level_index: TopLevelIndex) {
+        let table = Arc::new(CachedTable {
+            id: id as u64,
+            top_level_index,
+        });
+        self.tables
+            .lock()
             .unwrap()
-            .filter(|d| d.as_ref().unwrap().file_name() != ".gitkeep")
-            .collect::<Result<Vec<_>, _>>()
-            .unwrap();
+            .insert(file_name.to_string(), table);
+    }
 
-        entries.sort_by_key(|e| e.file_name());
+    /*
+     * Cached blocks, the open handle and the mapping of the file are dropped,
+     * has to happen before the file is removed
+     */
+    pub fn delete(&mut self, file_name: &str) {
+        if let Some(table) = self.tables.lock().unwrap().remove(file_name) {
+            self.block_cache.remove_file(table.id);
+        }
 
-        let seek = SeekFrom::End(-32);
-        for file in entries {
-            info!("Processing file: {:?}", file.path());
+        self.mmap_reader.release(file_name);
+        self.table_cache.evict(file_name);
+    }
 
-            let mut opened = File::open(file.path()).expect("Unable to open file");
-            opened.seek(seek).expect("Unable to seek to the position");
+    /*
+     * Limiter of the flushes and compactions writing into this cache
+     */
+    pub fn rate_limiter(&self) -> Arc<RateLimiter> {
+        Arc::clone(&self.rate_limiter)
+    }
 
-            let mut buffer = vec![0u8; 32];
-            opened
-                .read_exact(&mut buffer)
-                .expect("Unable to read the file");
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.block_cache.stats()
+    }
 
-            let footer: SSTableFooter = bincode::deserialize(&buffer).unwrap();
+    pub fn row_cache(&self) -> &RowCache {
+        &self.row_cache
+    }
 
-            self.read_indexes_from_file(
-                &mut opened,
-                file.path().to_str().unwrap().to_string(),
-                footer.index_offset,
-                footer.index_size,
-            );
+    pub fn table_cache(&self) -> &TableCache {
+        &self.table_cache
+    }
 
-            self.read_bloom_filter_from_file(
-                &mut opened,
-                file.path().to_str().unwrap().to_string(),
-                footer.bloom_filter_offset,
-                footer.bloom_filter_size,
-            );
-        }
+    pub fn mmap_reader(&self) -> &MmapReader {
+        &self.mmap_reader
     }
 
-    fn read_indexes_from_file(
-        &mut self,
-        file: &mut File,
-        file_name: String,
-        offset: u64,
-        size: u64,
-    ) {
-        let seek = SeekFrom::Start(offset);
-        file.seek(seek).expect("Unable to seek to the position");
+    /*
+     * Returns the metadata of the file, reading its top level index if this
+     * is the first access
+     */
+    fn table(&self, file_name: &str) -> Result<Arc<CachedTable>> {
+        if let Some(table) = self.tables.lock().unwrap().get(file_name) {
+            return Ok(Arc::clone(table));
+        }
 
-        let mut buffer = vec![0u8; size as usize];
-        file.read_exact(&mut buffer)
-            .expect("Unable to read the file");
+        info!("loading top level index of file {}", file_name);
 
-        let index_vector: Vec<IndexRecord> = bincode::deserialize(&buffer).unwrap();
+        let id = table_id(file_name).ok_or(LsmError::SsTable(format!(
+            "{} is not an ss_table file name",
+            file_name
+        )))?;
+        let top_level_index =
+            SSTableManager::read_top_level_index(&mut *self.table_cache.open(file_name)?.file())?;
 
-        info!("read index vector: {:?}", index_vector);
+        // another reader might have loaded the file in the meantime
+        let table = self
+            .tables
+            .lock()
+            .unwrap()
+            .entry(file_name.to_string())
+            .or_insert_with(|| {
+                Arc::new(CachedTable {
+                    id: id as u64,
+                    top_level_index,
+                })
+            })
+            .clone();
 
-        self.indexes.insert(file_name, index_vector);
+        Ok(table)
     }
 
-    fn read_bloom_filter_from_file(
-        &mut self,
-        file: &mut File,
-        file_name: String,
-        offset: u64,
-        size: u64,
-    ) {
-        let seek = SeekFrom::Start(offset);
-        file.seek(seek).expect("Unable to seek to the position");
+    /*
+     * Returns the entries of a data block, reading it from the file if it is
+     * not cached
+     */
+    pub fn data_block(
+        &self,
+        file_name: &str,
+        index_record: &IndexRecord,
+    ) -> Result<Arc<DataBlock>> {
+        let table = self.table(file_name)?;
+        let key = BlockKey {
+            file_id: table.id,
+            offset: index_record.offset,
+        };
+
+        if let Some(Block::Data(entries)) = self.block_cache.get(&key) {
+            return Ok(entries);
+        }
+
+        let entries = Arc::new(SSTableManager::read_from_file(
+            self,
+            file_name,
+            index_record,
+            table.top_level_index.version,
+        )?);
+
+        self.block_cache.insert(
+            key,
+            Block::Data(Arc::clone(&entries)),
+            index_record.size as usize,
+            Priority::Low,
+        );
 
-        let mut buffer = vec![0u8; size as usize];
-        file.read_exact(&mut buffer)
-            .expect("Unable to read the file");
+        Ok(entries)
+    }
 
-        info!("bloomfilter offset {} size {}", offset, size);
+    /*
+     * Returns the partition which could hold the key, loading it from the
+     * file if it is not cached
+     */
+    fn partition(&self, file_name: &str, key: &str) -> Result<Option<Arc<Partition>>> {
+        let table = self.table(file_name)?;
+        let Some(index_partition) = table
+            .top_level_index
+            .partitions
+            .iter()
+            .find(|p| p.start.as_str() <= key && p.end.as_str() >= key)
+        else {
+            return Ok(None);
+        };
 
-        let bloom_filter: BloomFilter = bincode::deserialize(&buffer).unwrap();
-        self.bloom_filters.insert(file_name, bloom_filter);
+        self.load_partition(file_name, &table, index_partition)
+            .map(Some)
     }
 
-    pub fn add(
-        &mut self,
+    fn load_partition(
+        &self,
         file_name: &str,
-        bloom_filter: BloomFilter,
-        index_vector: Vec<IndexRecord>,
-    ) {
-        self.bloom_filters
-            .insert(file_name.to_string(), bloom_filter);
-        self.indexes.insert(file_name.to_string(), index_vector);
+        table: &CachedTable,
+        index_partition: &IndexPartition,
+    ) -> Result<Arc<Partition>> {
+        let key = BlockKey {
+            file_id: table.id,
+            offset: index_partition.index.offset,
+        };
+        if let Some(Block::Partition(partition)) = self.block_cache.get(&key) {
+            return Ok(partition);
+        }
+
+        info!(
+            "loading partition at offset {} from file {}",
+            index_partition.index.offset, file_name
+        );
+
+        let (index, filter) = SSTableManager::read_index_partition(
+            &mut *self.table_cache.open(file_name)?.file(),
+            index_partition,
+            &table.top_level_index.filter_policy,
+        )?;
+
+        let partition = Arc::new(Partition { index, filter });
+        let priority = if Config::global().cache.pin_index_and_filter_blocks {
+            Priority::High
+        } else {
+            Priority::Low
+        };
+        self.block_cache.insert(
+            key,
+            Block::Partition(Arc::clone(&partition)),
+            (index_partition.index.size + index_partition.filter.size) as usize,
+            priority,
+        );
+
+        Ok(partition)
     }
 
-    pub fn delete(&mut self, file_name: &str) {
-        self.bloom_filters.remove(file_name);
-        self.indexes.remove(file_name);
+    pub fn may_contain(&self, file_name: &str, key: &str) -> Result<bool> {
+        Ok(self
+            .partition(file_name, key)?
+            .is_some_and(|partition| partition.filter.contains(key)))
+    }
+
+    /*
+     * Whether the file might hold a key in [start, end), files without a
+     * range filter can not be excluded
+     */
+    pub fn may_contain_range(&self, file_name: &str, start: &str, end: &str) -> Result<bool> {
+        Ok(self
+            .table(file_name)?
+            .top_level_index
+            .range_filter
+            .as_ref()
+            .is_none_or(|range_filter| range_filter.may_contain_range(start, end)))
     }
 
-    pub fn get(&self, key: &str) -> Vec<&str> {
-        self.bloom_filters
+    /*
+     * Data blocks of the file holding keys in [start, end), in key order
+     */
+    pub fn blocks_in_range(
+        &self,
+        file_name: &str,
+        start: &str,
+        end: &str,
+    ) -> Result<Vec<IndexRecord>> {
+        let table = self.table(file_name)?;
+
+        let mut result = Vec::new();
+        for index_partition in table
+            .top_level_index
+            .partitions
             .iter()
-            .filter(|(_, value)| value.contains(key))
-            .map(|(k, _)| k.as_str())
-            .collect::<Vec<&str>>()
+            .filter(|p| p.start.as_str() < end && p.end.as_str() >= start)
+        {
+            let partition = self.load_partition(file_name, &table, index_partition)?;
+            result.extend(
+                partition
+                    .index
+                    .iter()
+                    .filter(|i| i.start.as_str() < end && i.end.as_str() >= start)
+                    .cloned(),
+            );
+        }
+
+        Ok(result)
     }
 
-    pub fn seek_position(&self, file_name: &str, key: &str) -> Option<&IndexRecord> {
+    pub fn seek_position(&self, file_name: &str, key: &str) -> Result<Option<IndexRecord>> {
         info!(
             "looking for seek position for filename {} and key {}",
             file_name, key
         );
 
-        let file_indexes = self.indexes.get(file_name)?;
-        for index in file_indexes.iter() {
+        let Some(partition) = self.partition(file_name, key)? else {
+            return Ok(None);
+        };
+        for index in partition.index.iter() {
             if index.start.as_str() <= key && index.end.as_str() >= key {
                 info!("seek location found at {:?}", index);
-                return Some(index);
+                return Ok(Some(index.clone()));
             }
         }
         info!(
             "seek location not found for filename: {} and key:{}",
             file_name, key
         );
-        None
+        Ok(None)
     }
 }
@@ -0,0 +1,20 @@
+use std::fmt::Debug;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Remove,
+    ChangeValue(String),
+}
+
+/*
+ * Called by compaction for the newest value of every key merged, deleted
+ * keys are not passed. A removed entry is written as a tombstone as long as
+ * an older version of the key could still be found below the output.
+ * Tables are only filtered once they are compacted, reads see the values
+ * as they were written until then.
+ */
+pub trait CompactionFilter: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn filter(&self, level: usize, key: &str, value: &str) -> FilterDecision;
+}
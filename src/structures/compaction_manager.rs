@@ -1,196 +1,1033 @@
 use std::{
-    fs::{self, File},
-    io::{Read, Seek, SeekFrom},
-    sync::{Arc, RwLock},
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    sync::{
+        Arc, Condvar, Mutex, MutexGuard, RwLock, Weak,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
     time::Duration,
 };
 
-use log::trace;
+use log::{error, info, trace, warn};
 
 use crate::{
     config::Config,
+    error::{LsmError, Result},
     structures::{
-        cache::{Cache, IndexRecord},
-        manifest::Manifest,
-        memtable::MemTable,
-        ss_table_manager::{SSTableFooter, SSTableManager},
+        blob_manager::{BlobManager, BlobWriter},
+        cache::Cache,
+        compaction_filter::{CompactionFilter, FilterDecision},
+        compaction_strategy::{self, CompactionJob, CompactionStrategy, LevelScore},
+        manifest::{BlobFileInfo, Manifest, SSTableBasicInfo},
+        merge_iterator::{MergeIterator, MergedEntry},
+        rate_limiter::{IoPriority, RateLimiter},
+        ss_table_manager::{SSTableManager, SSTableValue, TableBuilder, TableIterator},
+        thread_pool::ThreadPool,
     },
 };
 
+/*
+ * Totals over every compaction since the start. Reclaimed bytes are the
+ * bytes of the input tables minus the bytes of the output tables, dropped
+ * tables are deleted without any output.
+ */
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    pub compactions: u64,
+    pub input_bytes: u64,
+    pub output_bytes: u64,
+    pub reclaimed_bytes: u64,
+    pub dropped_versions: u64,
+    pub dropped_tombstones: u64,
+    pub dropped_tables: u64,
+    pub removed_by_filter: u64,
+    pub changed_by_filter: u64,
+    pub subcompactions: u64,
+    pub trivial_moves: u64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    compactions: AtomicU64,
+    input_bytes: AtomicU64,
+    output_bytes: AtomicU64,
+    reclaimed_bytes: AtomicU64,
+    dropped_versions: AtomicU64,
+    dropped_tombstones: AtomicU64,
+    dropped_tables: AtomicU64,
+    removed_by_filter: AtomicU64,
+    changed_by_filter: AtomicU64,
+    subcompactions: AtomicU64,
+    trivial_moves: AtomicU64,
+}
+
+/*
+ * Tables and key range of a compaction in progress, None is a range
+ * without bounds
+ */
+#[derive(Debug)]
+struct RunningJob {
+    tables: BTreeSet<usize>,
+    key_range: Option<(String, String)>,
+}
+
+/*
+ * Takes the job off the running ones once dropped, also when the compaction
+ * panics, which counts as a failure
+ */
+#[derive(Debug)]
+struct FinishGuard<'a> {
+    manager: &'a Arc<CompactionManager>,
+    tables: BTreeSet<usize>,
+    succeeded: bool,
+}
+
+/*
+ * Tables and blob files a compaction wrote so far, they only become live
+ * once every subcompaction is done
+ */
+#[derive(Debug, Default)]
+struct Outputs {
+    ss_tables: Vec<SSTableBasicInfo>,
+    blob_files: Vec<BlobFileInfo>,
+}
+
 #[derive(Debug)]
 pub struct CompactionManager {
     manifest: Arc<RwLock<Manifest>>,
     cache: Arc<RwLock<Cache>>,
+    strategy: Box<dyn CompactionStrategy>,
+    filter: Option<Box<dyn CompactionFilter>>,
+    running: Mutex<Vec<RunningJob>>,
+    finished: Condvar,
+    pool: ThreadPool,
+    counters: Counters,
+}
+
+impl RunningJob {
+    fn new(job: &CompactionJob) -> Self {
+        let bounded = job.inputs.iter().all(|t| t.has_key_range());
+        let min_key = job.inputs.iter().map(|t| &t.min_key).min();
+        let max_key = job.inputs.iter().map(|t| &t.max_key).max();
+
+        Self {
+            tables: job.inputs.iter().map(|t| t.id).collect(),
+            key_range: min_key
+                .zip(max_key)
+                .filter(|_| bounded)
+                .map(|(min, max)| (min.clone(), max.clone())),
+        }
+    }
+
+    /*
+     * Jobs sharing a table or writing overlapping keys can't run at the same
+     * time, the output of one could end up older than the output of the
+     * other or miss versions the other one is moving
+     */
+    fn conflicts(&self, other: &RunningJob) -> bool {
+        if !self.tables.is_disjoint(&other.tables) {
+            return true;
+        }
+
+        match (&self.key_range, &other.key_range) {
+            (Some((min1, max1)), Some((min2, max2))) => min1 <= max2 && min2 <= max1,
+            _ => true,
+        }
+    }
+}
+
+impl Drop for FinishGuard<'_> {
+    fn drop(&mut self) {
+        self.manager.finish(&self.tables, self.succeeded);
+    }
 }
 
 impl CompactionManager {
-    pub fn new(manifest: Arc<RwLock<Manifest>>, cache: Arc<RwLock<Cache>>) -> Self {
-        Self { manifest, cache }
+    /*
+     * The strategy is the one recorded in the manifest
+     */
+    pub fn new(manifest: Arc<RwLock<Manifest>>, cache: Arc<RwLock<Cache>>) -> Result<Self> {
+        let strategy = compaction_strategy::from_name(
+            manifest
+                .read()
+                .expect("Unable to aquire read lock")
+                .compaction_style(),
+        )?;
+
+        Ok(Self {
+            manifest,
+            cache,
+            strategy,
+            filter: None,
+            running: Mutex::new(Vec::new()),
+            finished: Condvar::new(),
+            pool: ThreadPool::new(
+                "compaction",
+                Config::global().ss_table.max_background_compactions,
+            ),
+            counters: Counters::default(),
+        })
+    }
+
+    fn rate_limiter(&self) -> Arc<RateLimiter> {
+        self.cache
+            .read()
+            .expect("Unable to get lock to cache")
+            .rate_limiter()
+    }
+
+    pub fn with_filter(mut self, filter: Box<dyn CompactionFilter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    pub fn stats(&self) -> CompactionStats {
+        CompactionStats {
+            compactions: self.counters.compactions.load(Ordering::Relaxed),
+            input_bytes: self.counters.input_bytes.load(Ordering::Relaxed),
+            output_bytes: self.counters.output_bytes.load(Ordering::Relaxed),
+            reclaimed_bytes: self.counters.reclaimed_bytes.load(Ordering::Relaxed),
+            dropped_versions: self.counters.dropped_versions.load(Ordering::Relaxed),
+            dropped_tombstones: self.counters.dropped_tombstones.load(Ordering::Relaxed),
+            dropped_tables: self.counters.dropped_tables.load(Ordering::Relaxed),
+            removed_by_filter: self.counters.removed_by_filter.load(Ordering::Relaxed),
+            changed_by_filter: self.counters.changed_by_filter.load(Ordering::Relaxed),
+            subcompactions: self.counters.subcompactions.load(Ordering::Relaxed),
+            trivial_moves: self.counters.trivial_moves.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn scores(&self) -> Vec<LevelScore> {
+        self.strategy
+            .scores(&self.manifest.read().expect("Unable to aquire read lock"))
     }
 
-    pub async fn monitor(&self) {
+    /*
+     * Flushes schedule compactions as soon as they are done, the monitor
+     * runs every 30 seconds so expired tables are dropped without any
+     * writes. Holds the manager only while scheduling, so it stops once the
+     * manager is dropped.
+     */
+    pub async fn monitor(manager: Weak<CompactionManager>) {
         loop {
-            self.monitor_l0();
-            self.monitor_l1();
             tokio::time::sleep(Duration::from_secs(30)).await;
+
+            match manager.upgrade() {
+                Some(manager) => manager.schedule(),
+                None => return,
+            }
         }
     }
 
-    pub fn monitor_l0(&self) {
-        let ss_table_count = self
-            .manifest
-            .read()
-            .expect("Unable to aquire read lock")
-            .ss_tables_in_level(0)
-            .len();
+    /*
+     * Blocks until no compaction is running. A finished compaction schedules
+     * the next ones before it is taken off the running ones, so nothing
+     * is left to do once this returns.
+     */
+    pub fn wait_for_compactions(&self) {
+        let mut running = self.running.lock().unwrap();
+        while !running.is_empty() {
+            running = self.finished.wait(running).unwrap();
+        }
+    }
 
-        if ss_table_count >= Config::global().ss_table.l0_file_count_limit {
-            self.compact(0);
-        } else {
-            trace!("nothing to do for compaction")
+    /*
+     * Blocks the writer while L0 holds l0_stop_writes_trigger tables or
+     * more, compactions are scheduled right away. Gives up once nothing is
+     * left running, a strategy may never shrink L0.
+     */
+    pub fn stall_writes(self: &Arc<Self>) {
+        let limit = Config::global().ss_table.l0_stop_writes_trigger;
+        let l0_count = || {
+            self.manifest
+                .read()
+                .expect("Unable to aquire read lock")
+                .ss_tables_in_level(0)
+                .len()
+        };
+
+        if l0_count() < limit {
+            return;
+        }
+
+        warn!("stalling writes, L0 holds {} ss_tables", l0_count());
+        self.schedule();
+
+        let mut running = self.running.lock().unwrap();
+        while !running.is_empty() && l0_count() >= limit {
+            running = self
+                .finished
+                .wait_timeout(running, Duration::from_secs(1))
+                .unwrap()
+                .0;
         }
     }
 
-    pub fn monitor_l1(&self) {
-        let ss_table_count = self
-            .manifest
-            .read()
-            .expect("Unable to aquire read lock")
-            .ss_tables_in_level(1)
-            .len();
+    /*
+     * Hands compactions to the background threads until every thread is
+     * busy or the strategy finds nothing left which doesn't conflict with a
+     * running compaction. Every finished compaction schedules the next ones,
+     * a failed compaction is retried on the next run of the monitor.
+     */
+    pub fn schedule(self: &Arc<Self>) {
+        self.schedule_locked(&mut self.running.lock().unwrap());
+    }
 
-        if ss_table_count >= Config::global().ss_table.l1_file_size_upper_limit {
-            self.compact(1);
-        } else {
-            trace!("nothing to do for compaction")
+    fn schedule_locked(self: &Arc<Self>, running: &mut MutexGuard<Vec<RunningJob>>) {
+        if let Err(e) = self.drop_expired() {
+            error!("Dropping expired ss_tables failed: {}", e);
+        }
+
+        let mut passed_over = BTreeSet::new();
+        while running.len() < self.pool.size() {
+            let busy = running
+                .iter()
+                .flat_map(|r| r.tables.iter().copied())
+                .chain(passed_over.iter().copied())
+                .collect::<BTreeSet<usize>>();
+
+            let Some(job) = self.pick(&busy) else {
+                trace!("nothing to do for compaction");
+                break;
+            };
+
+            let running_job = RunningJob::new(&job);
+            if running.iter().any(|r| r.conflicts(&running_job)) {
+                trace!("{:?} conflicts with a running compaction", running_job);
+                passed_over.extend(running_job.tables);
+                continue;
+            }
+
+            running.push(running_job);
+
+            let manager = Arc::clone(self);
+            self.pool.execute(move || {
+                let mut finish = FinishGuard {
+                    manager: &manager,
+                    tables: job.inputs.iter().map(|t| t.id).collect(),
+                    succeeded: false,
+                };
+                let trivial_move = job
+                    .is_trivial_move(&manager.manifest.read().expect("Unable to aquire read lock"));
+                let result = if trivial_move {
+                    manager.move_tables(job)
+                } else {
+                    manager.compact(job)
+                };
+
+                if let Err(e) = &result {
+                    error!("Compaction failed: {}", e);
+                }
+                finish.succeeded = result.is_ok();
+            });
         }
     }
 
-    pub fn compact(&self, level: usize) {
-        trace!("starting compaction for level {}", level);
-        trace!("giong to compact {:?}", self.manifest);
+    /*
+     * Takes the job off the running ones, after a success the next ones
+     * are scheduled first
+     */
+    fn finish(self: &Arc<Self>, tables: &BTreeSet<usize>, schedule: bool) {
+        let mut running = self.running.lock().unwrap();
+        running.retain(|r| r.tables != *tables);
 
-        let ss_tables = self
-            .manifest
+        if schedule {
+            self.schedule_locked(&mut running);
+        }
+
+        drop(running);
+        self.finished.notify_all();
+    }
+
+    fn drop_expired(&self) -> Result<()> {
+        let expired = self
+            .strategy
+            .expired(&self.manifest.read().expect("Unable to aquire read lock"));
+        if !expired.is_empty() {
+            self.drop_tables(expired)?;
+        }
+
+        Ok(())
+    }
+
+    /*
+     * Merges every table overlapping [start, end] down to the target level,
+     * together with every table the output would overlap otherwise. Only
+     * entries already persisted to ss_tables are compacted. Waits for running
+     * compactions of the same keys, then runs on the calling thread.
+     */
+    pub fn compact_range(
+        self: &Arc<Self>,
+        start: &str,
+        end: &str,
+        target_level: usize,
+    ) -> Result<()> {
+        if !self.strategy.merges_tables() {
+            return Err(LsmError::SsTable(format!(
+                "{} compaction never merges tables",
+                self.strategy.name()
+            )));
+        }
+
+        // an output in L0 would get a newer id than flushes of newer writes
+        if target_level == 0 {
+            return Err(LsmError::SsTable(
+                "ranges are compacted into level 1 or below".to_owned(),
+            ));
+        }
+
+        if target_level >= Config::global().ss_table.max_levels {
+            return Err(LsmError::SsTable(format!(
+                "level {} is beyond the last level",
+                target_level
+            )));
+        }
+
+        let mut running = self.running.lock().unwrap();
+
+        let job = loop {
+            let inputs = self
+                .manifest
+                .read()
+                .expect("Unable to aquire read lock")
+                .range_nominees(start, end, target_level);
+
+            let Some(level) = inputs.iter().map(|t| t.level).min() else {
+                trace!("no ss_table overlaps {} to {}", start, end);
+                return Ok(());
+            };
+
+            let job = CompactionJob {
+                level,
+                inputs,
+                output_level: target_level,
+            };
+
+            let running_job = RunningJob::new(&job);
+            if !running.iter().any(|r| r.conflicts(&running_job)) {
+                running.push(running_job);
+                break job;
+            }
+
+            trace!("waiting for compactions of {} to {}", start, end);
+            running = self.finished.wait(running).unwrap();
+        };
+        drop(running);
+
+        let mut finish = FinishGuard {
+            manager: self,
+            tables: job.inputs.iter().map(|t| t.id).collect(),
+            succeeded: false,
+        };
+        let result = self.compact(job);
+        finish.succeeded = result.is_ok();
+
+        result
+    }
+
+    fn pick(&self, busy: &BTreeSet<usize>) -> Option<CompactionJob> {
+        self.strategy.pick(
+            &mut self
+                .manifest
+                .write()
+                .expect("Unable to get lock on manifest"),
+            busy,
+        )
+    }
+
+    pub fn compact(&self, job: CompactionJob) -> Result<()> {
+        trace!(
+            "starting {} compaction of level {} into level {}",
+            self.strategy.name(),
+            job.level,
+            job.output_level
+        );
+        trace!("compaction nominees are {:?} ", job.inputs);
+
+        let paths = job
+            .inputs
+            .iter()
+            .map(|t| t.path.as_str())
+            .collect::<Vec<&str>>();
+        let wal_index_range = self.wal_index_range(&paths)?;
+        let expected_keys = self.expected_keys(&paths)?;
+        let older_tables = self.older_tables(job.output_level, &job.inputs);
+        let input_bytes = job.inputs.iter().map(|t| t.file_size).sum();
+        let ranges = self.subcompaction_ranges(&job.inputs, input_bytes);
+        let outputs = Mutex::new(Outputs::default());
+
+        let results = if ranges.len() == 1 {
+            vec![self.subcompact(
+                &job,
+                (wal_index_range, expected_keys),
+                &older_tables,
+                (None, None),
+                &outputs,
+            )]
+        } else {
+            trace!(
+                "splitting the compaction into {} subcompactions",
+                ranges.len()
+            );
+
+            thread::scope(|scope| {
+                let handles = ranges
+                    .into_iter()
+                    .map(|range| {
+                        scope.spawn(|| {
+                            self.subcompact(
+                                &job,
+                                (wal_index_range, expected_keys),
+                                &older_tables,
+                                range,
+                                &outputs,
+                            )
+                        })
+                    })
+                    .collect::<Vec<_>>();
+
+                handles
+                    .into_iter()
+                    .map(|handle| {
+                        handle.join().unwrap_or_else(|_| {
+                            Err(LsmError::SsTable("subcompaction panicked".to_string()))
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        let mut stats = CompactionStats {
+            compactions: 1,
+            input_bytes,
+            subcompactions: if results.len() > 1 {
+                results.len() as u64
+            } else {
+                0
+            },
+            ..Default::default()
+        };
+        let outputs = outputs.into_inner().unwrap();
+
+        // the outputs of a failed compaction never made it into the manifest
+        let results = match results.into_iter().collect::<Result<Vec<_>>>() {
+            Ok(results) => results,
+            Err(e) => {
+                outputs.ss_tables.iter().for_each(|t| self.release_file(t));
+                outputs.blob_files.iter().for_each(|b| {
+                    let _ = fs::remove_file(&b.path);
+                });
+                return Err(e);
+            }
+        };
+
+        let mut garbage = BTreeMap::new();
+        for (sub_stats, sub_garbage) in results {
+            stats.output_bytes += sub_stats.output_bytes;
+            stats.dropped_versions += sub_stats.dropped_versions;
+            stats.dropped_tombstones += sub_stats.dropped_tombstones;
+            stats.removed_by_filter += sub_stats.removed_by_filter;
+            stats.changed_by_filter += sub_stats.changed_by_filter;
+            sub_garbage
+                .into_iter()
+                .for_each(|(id, bytes)| *garbage.entry(id).or_default() += bytes);
+        }
+
+        self.manifest
             .write()
-            .expect("Unable to aquire read lock")
-            .compaction_nominees(level);
+            .expect("Unable to get lock on manifest")
+            .replace(&job.inputs, outputs.ss_tables, outputs.blob_files);
+        job.inputs.iter().for_each(|t| self.release_file(t));
+        self.collect_blob_garbage(garbage);
+
+        // rows the filter removed or changed may still be cached with their old value
+        if stats.removed_by_filter + stats.changed_by_filter > 0 {
+            self.cache
+                .read()
+                .expect("Unable to get lock to cache")
+                .row_cache()
+                .clear();
+        }
+
+        // tables written before their size was recorded count as 0 bytes
+        stats.reclaimed_bytes = stats.input_bytes.saturating_sub(stats.output_bytes);
+        info!("compaction of level {} done {:?}", job.level, stats);
+        self.add_stats(&stats);
+
+        Ok(())
+    }
 
-        trace!("compaction nominees are {:?} ", ss_tables);
+    /*
+     * Compactions of more than twice target_file_size bytes are split at the
+     * smallest keys of their input tables, spread evenly over them. Every
+     * range is [start, end), None is unbounded.
+     */
+    fn subcompaction_ranges(
+        &self,
+        inputs: &[SSTableBasicInfo],
+        input_bytes: u64,
+    ) -> Vec<(Option<String>, Option<String>)> {
+        let target_file_size = self.strategy.target_file_size();
+        let whole = vec![(None, None)];
 
-        let path1 = ss_tables[0].path.as_str();
-        let values1 = self
-            .read_file_into_index_record(path1)
+        if input_bytes <= target_file_size.saturating_mul(2) {
+            return whole;
+        }
+
+        let keys = inputs
             .iter()
-            .flat_map(|i| SSTableManager::read_from_file(path1, i))
-            .collect::<Vec<(String, Option<String>)>>();
+            .filter(|t| t.has_key_range())
+            .map(|t| t.min_key.clone())
+            .collect::<BTreeSet<String>>()
+            .into_iter()
+            .collect::<Vec<String>>();
 
-        let path2 = ss_tables[1].path.as_str();
-        let values2 = self
-            .read_file_into_index_record(path2)
+        let count = (input_bytes / target_file_size)
+            .min(Config::global().ss_table.max_subcompactions as u64)
+            .min(keys.len() as u64) as usize;
+
+        if count < 2 {
+            return whole;
+        }
+
+        let boundaries = (1..count)
+            .map(|i| keys[i * keys.len() / count].clone())
+            .collect::<BTreeSet<String>>();
+
+        let starts = std::iter::once(None).chain(boundaries.iter().cloned().map(Some));
+        let ends = boundaries
             .iter()
-            .flat_map(|i| SSTableManager::read_from_file(path2, i))
-            .collect::<Vec<(String, Option<String>)>>();
+            .cloned()
+            .map(Some)
+            .chain(std::iter::once(None));
 
-        trace!("read value vector: {:?}", values1);
-        trace!("read value vector: {:?}", values2);
+        starts.zip(ends).collect()
+    }
 
-        let mut index1 = 0;
-        let mut index2 = 0;
+    /*
+     * Merges the entries of the job within the range into tables of the
+     * output level, which are added to the outputs. Entries are streamed
+     * into the table being written. Returns the stats and the blob garbage
+     * of the range.
+     */
+    fn subcompact(
+        &self,
+        job: &CompactionJob,
+        (wal_index_range, expected_keys): ((u64, u64), usize),
+        older_tables: &[SSTableBasicInfo],
+        (start, end): (Option<String>, Option<String>),
+        outputs: &Mutex<Outputs>,
+    ) -> Result<(CompactionStats, BTreeMap<usize, u64>)> {
+        // nominees are ordered from the oldest to the newest, which is the order the merge expects
+        let rate_limiter = self.rate_limiter();
+        let inputs = job
+            .inputs
+            .iter()
+            .map(|t| {
+                TableIterator::open(&t.path, Arc::clone(&rate_limiter))
+                    .map(|i| i.with_range(start.clone(), end.clone()))
+            })
+            .collect::<Result<Vec<TableIterator>>>()?;
 
-        let mut result = MemTable::default();
+        let target_file_size = self.strategy.target_file_size();
+        let mut stats = CompactionStats::default();
+        let mut garbage = BTreeMap::new();
+        let mut blob_writer = BlobWriter::new(Arc::clone(&self.manifest));
+        let mut output: Option<TableBuilder> = None;
+        let mut output_size = 0;
 
-        loop {
-            if index1 >= values1.len() && index2 >= values2.len() {
-                break;
-            }
+        let merge = || -> Result<()> {
+            for entry in MergeIterator::new(inputs) {
+                let entry = entry?;
 
-            if index1 >= values1.len() {
-                self.add_to_mem_table(&mut result, &values2[index2]);
-                index2 += 1;
-                continue;
+                trace!("merged entry {:?}", entry);
+
+                entry
+                    .shadowed
+                    .iter()
+                    .for_each(|value| self.add_garbage(&mut garbage, value));
+                stats.dropped_versions += entry.shadowed.len() as u64;
+
+                let MergedEntry { key, value, .. } = entry;
+                let value = self.apply_filter(
+                    job.level,
+                    &key,
+                    value,
+                    &mut blob_writer,
+                    &mut garbage,
+                    &mut stats,
+                )?;
+
+                if Self::is_obsolete_tombstone(&key, &value, older_tables) {
+                    trace!("dropping tombstone of {}", key);
+                    stats.dropped_tombstones += 1;
+                    continue;
+                }
+
+                let value = self.relocate(value, &mut blob_writer, &mut garbage)?;
+
+                output_size += SSTableManager::entry_size(&key, &value);
+                let builder = match output.as_mut() {
+                    Some(builder) => builder,
+                    None => output.insert(TableBuilder::create(
+                        &self.manifest,
+                        job.output_level,
+                        wal_index_range,
+                        expected_keys,
+                        (Arc::clone(&rate_limiter), IoPriority::Low),
+                    )?),
+                };
+                builder.add(key, value)?;
+
+                // the output is split into tables of about target_file_size bytes, which do not overlap
+                if output_size >= target_file_size {
+                    let builder = output.take().expect("an output is open");
+                    stats.output_bytes += self.write_output(builder, &mut blob_writer, outputs)?;
+                    output_size = 0;
+                }
             }
 
-            if index2 >= values2.len() {
-                self.add_to_mem_table(&mut result, &values1[index1]);
-                index1 += 1;
-                continue;
+            if let Some(builder) = output.take() {
+                stats.output_bytes += self.write_output(builder, &mut blob_writer, outputs)?;
             }
 
-            let key1 = &values1[index1].0;
-            let key2 = &values2[index2].0;
+            Ok(())
+        };
 
-            if key1 <= key2 {
-                self.add_to_mem_table(&mut result, &values1[index1]);
-                index1 += 1;
-            } else {
-                self.add_to_mem_table(&mut result, &values2[index2]);
-                index2 += 1;
+        if let Err(e) = merge() {
+            if let Some(builder) = output.take() {
+                builder.abandon();
             }
+            blob_writer.abandon();
+            return Err(e);
         }
 
-        trace!("final memtable after compaction {:?}", result);
+        if let Some(blob_file) = blob_writer.close()? {
+            outputs.lock().unwrap().blob_files.push(blob_file);
+        }
 
-        SSTableManager::persist(
-            Arc::new(result),
-            Arc::clone(&self.cache),
-            Arc::clone(&self.manifest),
-            level + 1,
-        )
-        .expect("Unable to persist compacted MemTable");
+        Ok((stats, garbage))
+    }
 
-        self.manifest
-            .write()
-            .expect("Unable to get lock on manifest")
-            .remove(&ss_tables[0]);
+    /*
+     * Moves the tables to the output level without rewriting them, nothing
+     * of the output level overlaps them
+     */
+    fn move_tables(&self, job: CompactionJob) -> Result<()> {
+        for ss_table in job.inputs.iter() {
+            trace!(
+                "moving ss_table {} from level {} to level {}",
+                ss_table.id, job.level, job.output_level
+            );
+
+            self.manifest
+                .write()
+                .expect("Unable to get lock on manifest")
+                .move_table(ss_table, job.output_level)?;
+
+            self.release_file(ss_table);
+        }
+
+        info!(
+            "moved {} ss_tables from level {} to level {}",
+            job.inputs.len(),
+            job.level,
+            job.output_level
+        );
+        self.add_stats(&CompactionStats {
+            trivial_moves: job.inputs.len() as u64,
+            ..Default::default()
+        });
+
+        Ok(())
+    }
+
+    /*
+     * Deletes the tables without merging them, the blob values they point to
+     * become garbage
+     */
+    fn drop_tables(&self, ss_tables: Vec<SSTableBasicInfo>) -> Result<()> {
+        info!("dropping {} expired ss_tables", ss_tables.len());
+
+        let rate_limiter = self.rate_limiter();
+        let mut garbage = BTreeMap::new();
+        for ss_table in ss_tables.iter() {
+            for entry in TableIterator::open(&ss_table.path, Arc::clone(&rate_limiter))? {
+                let (_, value) = entry?;
+                self.add_garbage(&mut garbage, &value);
+            }
+        }
 
         self.manifest
             .write()
             .expect("Unable to get lock on manifest")
-            .remove(&ss_tables[1]);
+            .replace(&ss_tables, Vec::new(), Vec::new());
+        ss_tables.iter().for_each(|t| self.release_file(t));
+        self.collect_blob_garbage(garbage);
 
+        // reads of the dropped rows may still be cached with their old value
         self.cache
-            .write()
+            .read()
             .expect("Unable to get lock to cache")
-            .delete(path1);
+            .row_cache()
+            .clear();
 
+        let dropped_bytes = ss_tables.iter().map(|t| t.file_size).sum();
+        self.add_stats(&CompactionStats {
+            input_bytes: dropped_bytes,
+            reclaimed_bytes: dropped_bytes,
+            dropped_tables: ss_tables.len() as u64,
+            ..Default::default()
+        });
+
+        Ok(())
+    }
+
+    fn release_file(&self, ss_table: &SSTableBasicInfo) {
         self.cache
             .write()
             .expect("Unable to get lock to cache")
-            .delete(path2);
+            .delete(&ss_table.path);
+
+        let _ = fs::remove_file(&ss_table.path);
+    }
+
+    fn add_stats(&self, stats: &CompactionStats) {
+        let counters = &self.counters;
+        counters
+            .compactions
+            .fetch_add(stats.compactions, Ordering::Relaxed);
+        counters
+            .input_bytes
+            .fetch_add(stats.input_bytes, Ordering::Relaxed);
+        counters
+            .output_bytes
+            .fetch_add(stats.output_bytes, Ordering::Relaxed);
+        counters
+            .reclaimed_bytes
+            .fetch_add(stats.reclaimed_bytes, Ordering::Relaxed);
+        counters
+            .dropped_versions
+            .fetch_add(stats.dropped_versions, Ordering::Relaxed);
+        counters
+            .dropped_tombstones
+            .fetch_add(stats.dropped_tombstones, Ordering::Relaxed);
+        counters
+            .dropped_tables
+            .fetch_add(stats.dropped_tables, Ordering::Relaxed);
+        counters
+            .removed_by_filter
+            .fetch_add(stats.removed_by_filter, Ordering::Relaxed);
+        counters
+            .changed_by_filter
+            .fetch_add(stats.changed_by_filter, Ordering::Relaxed);
+        counters
+            .subcompactions
+            .fetch_add(stats.subcompactions, Ordering::Relaxed);
+        counters
+            .trivial_moves
+            .fetch_add(stats.trivial_moves, Ordering::Relaxed);
+    }
+
+    /*
+     * Tables of the output level which are not merged and the tables below
+     * it hold older versions of their keys
+     */
+    fn older_tables(
+        &self,
+        output_level: usize,
+        inputs: &[SSTableBasicInfo],
+    ) -> Vec<SSTableBasicInfo> {
+        self.manifest
+            .read()
+            .expect("Unable to aquire read lock")
+            .ss_tables()
+            .iter()
+            .filter(|t| t.level >= output_level && inputs.iter().all(|i| i.id != t.id))
+            .cloned()
+            .collect()
+    }
+
+    /*
+     * A tombstone is only needed while an older version of the key could
+     * still be found below the output. There are no snapshots, so no reader
+     * ever needs a version older than the newest one.
+     */
+    fn is_obsolete_tombstone(
+        key: &str,
+        value: &Option<SSTableValue>,
+        older_tables: &[SSTableBasicInfo],
+    ) -> bool {
+        value.is_none() && !older_tables.iter().any(|t| t.may_contain(key))
+    }
+
+    /*
+     * A removed entry turns into a tombstone, the replaced value becomes
+     * garbage if it is a blob
+     */
+    fn apply_filter(
+        &self,
+        level: usize,
+        key: &str,
+        value: Option<SSTableValue>,
+        blob_writer: &mut BlobWriter,
+        garbage: &mut BTreeMap<usize, u64>,
+        stats: &mut CompactionStats,
+    ) -> Result<Option<SSTableValue>> {
+        let (Some(filter), Some(current)) = (self.filter.as_ref(), value.as_ref()) else {
+            return Ok(value);
+        };
+
+        let resolved = match current {
+            SSTableValue::Inline(current) => current.clone(),
+            SSTableValue::Blob(pointer) => BlobManager::read(pointer)?,
+        };
+
+        match filter.filter(level, key, &resolved) {
+            FilterDecision::Keep => Ok(value),
+            FilterDecision::Remove => {
+                trace!("{} removed {}", filter.name(), key);
+                self.add_garbage(garbage, &value);
+                stats.removed_by_filter += 1;
+                Ok(None)
+            }
+            FilterDecision::ChangeValue(changed) => {
+                trace!("{} changed the value of {}", filter.name(), key);
+                self.add_garbage(garbage, &value);
+                stats.changed_by_filter += 1;
+                Ok(Some(blob_writer.separate(&changed)?))
+            }
+        }
+    }
+
+    /*
+     * Blob values of shadowed entries become garbage
+     */
+    fn add_garbage(&self, garbage: &mut BTreeMap<usize, u64>, value: &Option<SSTableValue>) {
+        if let Some(SSTableValue::Blob(pointer)) = value {
+            *garbage.entry(pointer.file_id).or_default() += pointer.size;
+        }
+    }
 
-        let _ = fs::remove_file(path1);
-        let _ = fs::remove_file(path2);
+    /*
+     * The blob file holding the values relocated so far is closed along
+     * with the ss_table pointing into it, the next values go to a new one
+     */
+    fn write_output(
+        &self,
+        builder: TableBuilder,
+        blob_writer: &mut BlobWriter,
+        outputs: &Mutex<Outputs>,
+    ) -> Result<u64> {
+        match std::mem::replace(blob_writer, BlobWriter::new(Arc::clone(&self.manifest))).close() {
+            Ok(Some(blob_file)) => outputs.lock().unwrap().blob_files.push(blob_file),
+            Ok(None) => {}
+            Err(e) => {
+                builder.abandon();
+                return Err(e);
+            }
+        }
+
+        let ss_table = builder.finish(&self.cache)?;
+
+        let file_size = ss_table.file_size;
+        outputs.lock().unwrap().ss_tables.push(ss_table);
+
+        Ok(file_size)
     }
 
-    fn add_to_mem_table(&self, res: &mut MemTable, (key, value): &(String, Option<String>)) {
-        match value {
-            Some(value) => res.add(key, value),
-            None => res.delete(key),
+    /*
+     * Moves live values out of blob files that are mostly garbage, so the
+     * blob file can be deleted once nothing points into it anymore
+     */
+    fn relocate(
+        &self,
+        value: Option<SSTableValue>,
+        blob_writer: &mut BlobWriter,
+        garbage: &mut BTreeMap<usize, u64>,
+    ) -> Result<Option<SSTableValue>> {
+        let Some(SSTableValue::Blob(pointer)) = value else {
+            return Ok(value);
+        };
+
+        let garbage_ratio = self
+            .manifest
+            .read()
+            .expect("Unable to aquire read lock")
+            .blob_file(pointer.file_id)
+            .map_or(0.0, |b| b.garbage_ratio());
+
+        if garbage_ratio < Config::global().blob.gc_garbage_ratio {
+            return Ok(Some(SSTableValue::Blob(pointer)));
         }
+
+        trace!("relocating blob value {:?}", pointer);
+
+        let relocated = blob_writer.separate(&BlobManager::read(&pointer)?)?;
+        *garbage.entry(pointer.file_id).or_default() += pointer.size;
+
+        Ok(Some(relocated))
     }
 
-    fn read_file_into_index_record(&self, file_path: &str) -> Vec<IndexRecord> {
-        trace!("Processing file: {:?}", file_path);
+    /*
+     * Tables written before the properties block existed don't narrow the range
+     */
+    fn wal_index_range(&self, paths: &[&str]) -> Result<(u64, u64)> {
+        let cache = self.cache.read().expect("Unable to get lock to cache");
 
-        let seek = SeekFrom::End(-32);
+        let mut range = None;
+        for path in paths {
+            let Some(properties) = SSTableManager::read_properties(&cache, path)? else {
+                continue;
+            };
+
+            let (min, max) = range.unwrap_or((u64::MAX, 0));
+            range = Some((
+                min.min(properties.smallest_wal_index),
+                max.max(properties.largest_wal_index),
+            ));
+        }
+
+        Ok(range.unwrap_or_default())
+    }
+
+    /*
+     * Upper bound of the keys of one output table, which sizes its range
+     * filter. An output holds at most every input entry and about
+     * target_file_size bytes of keys and values. Tables written before the
+     * properties block existed are not counted.
+     */
+    fn expected_keys(&self, paths: &[&str]) -> Result<usize> {
+        let cache = self.cache.read().expect("Unable to get lock to cache");
 
-        let mut file = File::open(file_path).expect("Unable to open file");
-        file.seek(seek).expect("Unable to seek to the position");
+        let (mut entries, mut key_bytes) = (0u64, 0u64);
+        for path in paths {
+            if let Some(properties) = SSTableManager::read_properties(&cache, path)? {
+                entries += properties.entry_count;
+                key_bytes += properties.raw_key_size;
+            }
+        }
 
-        let mut buffer = vec![0u8; 32];
-        file.read_exact(&mut buffer)
-            .expect("Unable to read the file");
+        let average_key_size = key_bytes.checked_div(entries).unwrap_or(0).max(1);
+        let per_output = (self.strategy.target_file_size() / average_key_size).saturating_add(1);
 
-        let footer: SSTableFooter = bincode::deserialize(&buffer).unwrap();
+        Ok(entries.min(per_output) as usize)
+    }
 
-        let seek = SeekFrom::Start(footer.index_offset);
-        file.seek(seek).expect("Unable to seek to the position");
+    fn collect_blob_garbage(&self, garbage: BTreeMap<usize, u64>) {
+        let mut manifest = self
+            .manifest
+            .write()
+            .expect("Unable to get lock on manifest");
 
-        let mut buffer = vec![0u8; footer.index_size as usize];
-        file.read_exact(&mut buffer)
-            .expect("Unable to read the file");
+        garbage
+            .into_iter()
+            .for_each(|(id, bytes)| manifest.add_blob_garbage(id, bytes));
 
-        bincode::deserialize(&buffer).unwrap()
+        for blob_file in manifest.take_obsolete_blob_files() {
+            info!("removing obsolete blob file {}", blob_file.path);
+            let _ = fs::remove_file(&blob_file.path);
+        }
     }
 }
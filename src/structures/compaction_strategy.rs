@@ -0,0 +1,344 @@
+use std::collections::BTreeSet;
+use std::fmt::Debug;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::trace;
+
+use crate::config::Config;
+use crate::error::{LsmError, Result};
+use crate::structures::manifest::{Manifest, SSTableBasicInfo};
+
+/*
+ * Tables to merge into the output level, ordered from the oldest to the newest
+ */
+#[derive(Debug, Clone)]
+pub struct CompactionJob {
+    pub level: usize,
+    pub inputs: Vec<SSTableBasicInfo>,
+    pub output_level: usize,
+}
+
+impl CompactionJob {
+    /*
+     * Tables of a single level which neither overlap each other nor anything
+     * of the output level can change their level without being rewritten.
+     * A moved table keeps its id, a table of the output level overlapping it
+     * may have a higher id while holding older versions.
+     */
+    pub fn is_trivial_move(&self, manifest: &Manifest) -> bool {
+        if self.output_level <= self.level
+            || self
+                .inputs
+                .iter()
+                .any(|t| t.level != self.level || !t.has_key_range())
+        {
+            return false;
+        }
+
+        let mut inputs = self.inputs.iter().collect::<Vec<&SSTableBasicInfo>>();
+        inputs.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+
+        inputs.windows(2).all(|w| w[0].max_key < w[1].min_key)
+            && manifest
+                .ss_tables_in_level(self.output_level)
+                .iter()
+                .all(|t| !inputs.iter().any(|i| t.overlaps(&i.min_key, &i.max_key)))
+    }
+}
+
+/*
+ * How urgently a level needs compaction, from 1.0 on it is compacted
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelScore {
+    pub level: usize,
+    pub score: f64,
+}
+
+/*
+ * Decides which tables are merged when. The name of the strategy is
+ * recorded in the manifest, the layout of the levels depends on it.
+ * Busy tables are inputs of running compactions and must not be picked.
+ */
+pub trait CompactionStrategy: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn pick(&self, manifest: &mut Manifest, busy: &BTreeSet<usize>) -> Option<CompactionJob>;
+    fn target_file_size(&self) -> u64;
+
+    /*
+     * Scores of the levels, the highest first
+     */
+    fn scores(&self, manifest: &Manifest) -> Vec<LevelScore>;
+
+    /*
+     * Tables to delete without merging them anywhere
+     */
+    fn expired(&self, _manifest: &Manifest) -> Vec<SSTableBasicInfo> {
+        Vec::new()
+    }
+
+    fn merges_tables(&self) -> bool {
+        true
+    }
+}
+
+/*
+ * Every level above L0 holds non-overlapping tables and may grow to a byte
+ * target, level_size_multiplier times the target of the previous level.
+ * Reads check at most one table per level, at the cost of rewriting data
+ * on every level it passes.
+ */
+#[derive(Debug)]
+pub struct LeveledCompaction {
+    pub l0_file_count_limit: usize,
+    pub max_levels: usize,
+    pub max_bytes_for_level_base: u64,
+    pub level_size_multiplier: u64,
+    pub target_file_size: u64,
+}
+
+/*
+ * Every level is a tier of overlapping tables of about the same size. Once
+ * a tier holds min_merge_width tables they are merged into one table of the
+ * next tier, the last tier merges into itself. Every entry is written once
+ * per tier, reads may have to check every table of a tier.
+ */
+#[derive(Debug)]
+pub struct SizeTieredCompaction {
+    pub min_merge_width: usize,
+    pub max_levels: usize,
+}
+
+/*
+ * Tables are never merged, they stay in L0 until they are deleted, oldest
+ * first, once the tables hold more than max_size bytes or once a table is
+ * older than ttl_seconds. A limit of 0 is no limit. Meant for log-style data
+ * where only the most recent part is kept.
+ */
+#[derive(Debug)]
+pub struct FifoCompaction {
+    pub max_size: u64,
+    pub ttl_seconds: u64,
+}
+
+pub const LEVELED: &str = "leveled";
+pub const SIZE_TIERED: &str = "size_tiered";
+pub const FIFO: &str = "fifo";
+
+fn by_score(mut scores: Vec<LevelScore>) -> Vec<LevelScore> {
+    scores.sort_by(|a, b| b.score.total_cmp(&a.score));
+    scores
+}
+
+impl LeveledCompaction {
+    fn job(manifest: &mut Manifest, level: usize, busy: &BTreeSet<usize>) -> Option<CompactionJob> {
+        let inputs = manifest.compaction_nominees(level, busy);
+
+        if inputs.is_empty() {
+            return None;
+        }
+
+        Some(CompactionJob {
+            level,
+            inputs,
+            output_level: level + 1,
+        })
+    }
+}
+
+impl CompactionStrategy for LeveledCompaction {
+    fn name(&self) -> &'static str {
+        LEVELED
+    }
+
+    /*
+     * The level with the highest score goes first. A level whose tables
+     * are all busy is passed over.
+     */
+    fn pick(&self, manifest: &mut Manifest, busy: &BTreeSet<usize>) -> Option<CompactionJob> {
+        self.scores(manifest)
+            .into_iter()
+            .take_while(|s| s.score >= 1.0)
+            .find_map(|s| {
+                trace!("level {} has a score of {}", s.level, s.score);
+                LeveledCompaction::job(manifest, s.level, busy)
+            })
+    }
+
+    /*
+     * L0 is scored by its table count against l0_file_count_limit, since
+     * every L0 table is checked on reads, the other levels by their size
+     * against their target. The last level has no limit.
+     */
+    fn scores(&self, manifest: &Manifest) -> Vec<LevelScore> {
+        let l0_score = LevelScore {
+            level: 0,
+            score: manifest.ss_tables_in_level(0).len() as f64
+                / self.l0_file_count_limit.max(1) as f64,
+        };
+
+        let level_scores = (1..self.max_levels.saturating_sub(1)).map(|level| {
+            let target_size =
+                self.max_bytes_for_level_base * self.level_size_multiplier.pow(level as u32 - 1);
+
+            LevelScore {
+                level,
+                score: manifest.level_size(level) as f64 / target_size.max(1) as f64,
+            }
+        });
+
+        by_score(std::iter::once(l0_score).chain(level_scores).collect())
+    }
+
+    fn target_file_size(&self) -> u64 {
+        self.target_file_size
+    }
+}
+
+impl CompactionStrategy for SizeTieredCompaction {
+    fn name(&self) -> &'static str {
+        SIZE_TIERED
+    }
+
+    /*
+     * A whole tier is merged at once, so the output is newer than every
+     * table left in the next tier and older than every table of this one.
+     * Tiers holding a busy table wait until it is done.
+     */
+    fn pick(&self, manifest: &mut Manifest, busy: &BTreeSet<usize>) -> Option<CompactionJob> {
+        let last_level = self.max_levels.saturating_sub(1).max(1);
+
+        self.scores(manifest)
+            .into_iter()
+            .find_map(|LevelScore { level, .. }| {
+                let mut inputs = manifest
+                    .ss_tables_in_level(level)
+                    .into_iter()
+                    .cloned()
+                    .collect::<Vec<SSTableBasicInfo>>();
+
+                if inputs.len() < self.min_merge_width.max(2)
+                    || inputs.iter().any(|t| busy.contains(&t.id))
+                {
+                    return None;
+                }
+
+                inputs.sort_by_key(|t| t.id);
+
+                Some(CompactionJob {
+                    level,
+                    inputs,
+                    output_level: (level + 1).min(last_level),
+                })
+            })
+    }
+
+    /*
+     * A tier is one sorted run per table, the output is never split
+     */
+    fn target_file_size(&self) -> u64 {
+        u64::MAX
+    }
+
+    /*
+     * A tier is scored by its table count against min_merge_width
+     */
+    fn scores(&self, manifest: &Manifest) -> Vec<LevelScore> {
+        let last_level = self.max_levels.saturating_sub(1).max(1);
+        let width = self.min_merge_width.max(2) as f64;
+
+        by_score(
+            (0..=last_level)
+                .map(|level| LevelScore {
+                    level,
+                    score: manifest.ss_tables_in_level(level).len() as f64 / width,
+                })
+                .collect(),
+        )
+    }
+}
+
+impl CompactionStrategy for FifoCompaction {
+    fn name(&self) -> &'static str {
+        FIFO
+    }
+
+    fn pick(&self, _manifest: &mut Manifest, _busy: &BTreeSet<usize>) -> Option<CompactionJob> {
+        None
+    }
+
+    fn merges_tables(&self) -> bool {
+        false
+    }
+
+    fn target_file_size(&self) -> u64 {
+        u64::MAX
+    }
+
+    /*
+     * Every table stays in L0, scored by its size against max_size
+     */
+    fn scores(&self, manifest: &Manifest) -> Vec<LevelScore> {
+        if self.max_size == 0 {
+            return Vec::new();
+        }
+
+        vec![LevelScore {
+            level: 0,
+            score: manifest.level_size(0) as f64 / self.max_size as f64,
+        }]
+    }
+
+    /*
+     * Ids are handed out in the order the tables were written in, so the
+     * oldest tables have the lowest ids
+     */
+    fn expired(&self, manifest: &Manifest) -> Vec<SSTableBasicInfo> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        let mut tables = manifest.ss_tables().to_vec();
+        tables.sort_by_key(|t| t.id);
+
+        let mut total_size = tables.iter().map(|t| t.file_size).sum::<u64>();
+
+        tables
+            .into_iter()
+            .take_while(|t| {
+                let too_large = self.max_size > 0 && total_size > self.max_size;
+                let too_old =
+                    self.ttl_seconds > 0 && t.creation_time.saturating_add(self.ttl_seconds) < now;
+
+                total_size -= t.file_size;
+                too_large || too_old
+            })
+            .collect()
+    }
+}
+
+pub fn from_name(name: &str) -> Result<Box<dyn CompactionStrategy>> {
+    let config = &Config::global().ss_table;
+
+    match name {
+        LEVELED => Ok(Box::new(LeveledCompaction {
+            l0_file_count_limit: config.l0_file_count_limit,
+            max_levels: config.max_levels,
+            max_bytes_for_level_base: config.max_bytes_for_level_base,
+            level_size_multiplier: config.level_size_multiplier,
+            target_file_size: config.target_file_size,
+        })),
+        SIZE_TIERED => Ok(Box::new(SizeTieredCompaction {
+            min_merge_width: config.min_merge_width,
+            max_levels: config.max_levels,
+        })),
+        FIFO => Ok(Box::new(FifoCompaction {
+            max_size: config.fifo_max_size,
+            ttl_seconds: config.fifo_ttl_seconds,
+        })),
+        _ => Err(LsmError::SsTable(format!(
+            "unknown compaction style {}",
+            name
+        ))),
+    }
+}
@@ -0,0 +1,324 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::sync::Arc;
+
+use log::warn;
+
+use crate::structures::rate_limiter::{IoPriority, RateLimiter};
+
+/*
+ * O_DIRECT needs the buffer, the file offset and the length of every
+ * request to be aligned to the logical block size of the device.
+ */
+pub const ALIGNMENT: usize = 4096;
+const BUFFER_SIZE: usize = 64 * ALIGNMENT;
+
+/*
+ * Buffer whose data region starts at an aligned address, the vector is
+ * over allocated by one alignment to have room for shifting the start
+ */
+#[derive(Debug)]
+struct AlignedBuffer {
+    bytes: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    fn new(capacity: usize) -> Self {
+        let bytes = vec![0u8; capacity + ALIGNMENT];
+        let start = bytes.as_ptr().align_offset(ALIGNMENT);
+
+        Self {
+            bytes,
+            start,
+            len: 0,
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.len() - ALIGNMENT
+    }
+
+    fn region(&mut self) -> &mut [u8] {
+        let capacity = self.capacity();
+        &mut self.bytes[self.start..self.start + capacity]
+    }
+}
+
+/*
+ * Writer used for ss_tables, keeps track of the position so block offsets
+ * are known without asking the file. With direct I/O the bytes are staged
+ * in an aligned buffer and only whole aligned chunks reach the file.
+ */
+#[derive(Debug)]
+pub struct TableWriter {
+    file: File,
+    buffer: Option<AlignedBuffer>,
+    position: u64,
+    rate_limiter: Option<(Arc<RateLimiter>, IoPriority)>,
+}
+
+/*
+ * Only I/O with a rate limiter waits for its bytes
+ */
+fn charge(rate_limiter: &Option<(Arc<RateLimiter>, IoPriority)>, bytes: usize) {
+    if let Some((rate_limiter, priority)) = rate_limiter {
+        rate_limiter.request(bytes as u64, *priority);
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct(file_name: &str) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(file_name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct(_file_name: &str) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on linux",
+    ))
+}
+
+impl TableWriter {
+    pub fn create(file_name: &str, direct: bool) -> io::Result<Self> {
+        if direct {
+            match open_direct(file_name) {
+                Ok(file) => {
+                    return Ok(Self {
+                        file,
+                        buffer: Some(AlignedBuffer::new(BUFFER_SIZE)),
+                        position: 0,
+                        rate_limiter: None,
+                    });
+                }
+                Err(e) => warn!(
+                    "Unable to open {} for direct I/O, falling back to buffered writes: {}",
+                    file_name, e
+                ),
+            }
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(file_name)?;
+
+        Ok(Self {
+            file,
+            buffer: None,
+            position: 0,
+            rate_limiter: None,
+        })
+    }
+
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: Arc<RateLimiter>,
+        priority: IoPriority,
+    ) -> Self {
+        self.rate_limiter = Some((rate_limiter, priority));
+        self
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    /*
+     * Flushes the staged tail and syncs the file. The tail is padded to the
+     * alignment and the padding is cut off again afterwards.
+     */
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(buffer) = self.buffer.as_mut() {
+            let tail = buffer.len;
+            if tail > 0 {
+                let padded = tail.next_multiple_of(ALIGNMENT);
+                buffer.region()[tail..padded].fill(0);
+                self.file.write_all(&buffer.region()[..padded])?;
+            }
+
+            self.file.set_len(self.position)?;
+        }
+
+        self.file.sync_all()
+    }
+}
+
+impl Write for TableWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(buffer) = self.buffer.as_mut() else {
+            charge(&self.rate_limiter, buf.len());
+            let written = self.file.write(buf)?;
+            self.position += written as u64;
+            return Ok(written);
+        };
+
+        let start = buffer.len;
+        let written = buf.len().min(buffer.capacity() - start);
+        charge(&self.rate_limiter, written);
+        buffer.region()[start..start + written].copy_from_slice(&buf[..written]);
+        buffer.len += written;
+        self.position += written as u64;
+
+        if buffer.len == buffer.capacity() {
+            self.file.write_all(buffer.region())?;
+            buffer.len = 0;
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.buffer {
+            Some(_) => Ok(()),
+            None => self.file.flush(),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn open_direct_read(file_name: &str) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_DIRECT)
+        .open(file_name)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_direct_read(_file_name: &str) -> io::Result<File> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on linux",
+    ))
+}
+
+/*
+ * Reader used by compaction, reads the file through a window of one aligned
+ * buffer, so memory use does not depend on the size of the file. Without
+ * direct I/O the reads go straight to the file.
+ */
+#[derive(Debug)]
+pub struct TableScanner {
+    file: File,
+    buffer: Option<AlignedBuffer>,
+    window_start: u64,
+    position: u64,
+    len: u64,
+    rate_limiter: Option<(Arc<RateLimiter>, IoPriority)>,
+}
+
+impl TableScanner {
+    /*
+     * Falls back to buffered reads if the file system does not support direct I/O
+     */
+    pub fn open(file_name: &str, direct: bool) -> io::Result<Self> {
+        if direct {
+            match open_direct_read(file_name) {
+                Ok(file) => {
+                    return Ok(Self {
+                        len: file.metadata()?.len(),
+                        file,
+                        buffer: Some(AlignedBuffer::new(BUFFER_SIZE)),
+                        window_start: 0,
+                        position: 0,
+                        rate_limiter: None,
+                    });
+                }
+                Err(e) => warn!(
+                    "Unable to open {} for direct I/O, falling back to buffered reads: {}",
+                    file_name, e
+                ),
+            }
+        }
+
+        let file = File::open(file_name)?;
+
+        Ok(Self {
+            len: file.metadata()?.len(),
+            file,
+            buffer: None,
+            window_start: 0,
+            position: 0,
+            rate_limiter: None,
+        })
+    }
+
+    pub fn with_rate_limiter(
+        mut self,
+        rate_limiter: Arc<RateLimiter>,
+        priority: IoPriority,
+    ) -> Self {
+        self.rate_limiter = Some((rate_limiter, priority));
+        self
+    }
+}
+
+impl Read for TableScanner {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(buffer) = self.buffer.as_mut() else {
+            let read = self.file.read(buf)?;
+            charge(&self.rate_limiter, read);
+            return Ok(read);
+        };
+
+        if self.position >= self.len {
+            return Ok(0);
+        }
+
+        let window_end = self.window_start + buffer.len as u64;
+        if self.position < self.window_start || self.position >= window_end {
+            self.window_start = self.position - self.position % ALIGNMENT as u64;
+            self.file.seek(io::SeekFrom::Start(self.window_start))?;
+            buffer.len = self.file.read(buffer.region())?;
+            charge(&self.rate_limiter, buffer.len);
+
+            if buffer.len == 0 {
+                return Ok(0);
+            }
+        }
+
+        let start = (self.position - self.window_start) as usize;
+        let read = buf.len().min(buffer.len.saturating_sub(start));
+        if read == 0 {
+            return Ok(0);
+        }
+
+        buf[..read].copy_from_slice(&buffer.region()[start..start + read]);
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+impl Seek for TableScanner {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        if self.buffer.is_none() {
+            return self.file.seek(pos);
+        }
+
+        let position = match pos {
+            io::SeekFrom::Start(offset) => Some(offset),
+            io::SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            io::SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        self.position = position.ok_or(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative position",
+        ))?;
+
+        Ok(self.position)
+    }
+}
@@ -0,0 +1,171 @@
+use std::fmt::Debug;
+
+use crate::config::Config;
+use crate::error::{LsmError, Result};
+use crate::structures::blocked_bloom_filter::BlockedBloomFilter;
+use crate::structures::bloom_filter::BloomFilter;
+use crate::structures::xor_filter::XorFilter;
+
+pub trait KeyFilter: Debug + Send + Sync {
+    fn contains(&self, key: &str) -> bool;
+}
+
+/*
+ * Builds and decodes the filter partitions of an ss_table. The name of the
+ * policy is written into every ss_table, so a file is always read with the
+ * policy it was written with, whatever is configured now.
+ */
+pub trait FilterPolicy: Debug + Send + Sync {
+    fn name(&self) -> &'static str;
+    fn build(&self, keys: &[&str]) -> Result<Vec<u8>>;
+    fn decode(&self, bytes: &[u8]) -> Result<Box<dyn KeyFilter>>;
+}
+
+#[derive(Debug)]
+pub struct BloomFilterPolicy {
+    pub false_positive_rate: f64,
+}
+
+#[derive(Debug)]
+pub struct BlockedBloomFilterPolicy {
+    pub false_positive_rate: f64,
+}
+
+#[derive(Debug)]
+pub struct XorFilterPolicy {}
+
+/*
+ * Filters of files written before format version 4, only used for reading
+ */
+#[derive(Debug)]
+pub struct LegacyBloomFilterPolicy {}
+
+pub const BLOOM: &str = "bloom";
+pub const BLOCKED_BLOOM: &str = "blocked_bloom";
+pub const XOR: &str = "xor";
+pub const LEGACY_BLOOM: &str = "legacy_bloom";
+
+impl KeyFilter for BloomFilter {
+    fn contains(&self, key: &str) -> bool {
+        BloomFilter::contains(self, key)
+    }
+}
+
+impl KeyFilter for BlockedBloomFilter {
+    fn contains(&self, key: &str) -> bool {
+        BlockedBloomFilter::contains(self, key)
+    }
+}
+
+impl KeyFilter for XorFilter {
+    fn contains(&self, key: &str) -> bool {
+        XorFilter::contains(self, key)
+    }
+}
+
+fn serialize<T: serde::Serialize>(filter: &T) -> Vec<u8> {
+    bincode::serialize(filter).expect("Unable to serialize the filter")
+}
+
+fn deserialize<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T> {
+    bincode::deserialize(bytes).map_err(|e| LsmError::SsTable(e.to_string()))
+}
+
+impl FilterPolicy for BloomFilterPolicy {
+    fn name(&self) -> &'static str {
+        BLOOM
+    }
+
+    fn build(&self, keys: &[&str]) -> Result<Vec<u8>> {
+        Ok(serialize(&BloomFilter::from_keys(
+            keys,
+            self.false_positive_rate,
+        )))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Box<dyn KeyFilter>> {
+        Ok(Box::new(BloomFilter::decode(bytes, false)?))
+    }
+}
+
+impl FilterPolicy for BlockedBloomFilterPolicy {
+    fn name(&self) -> &'static str {
+        BLOCKED_BLOOM
+    }
+
+    fn build(&self, keys: &[&str]) -> Result<Vec<u8>> {
+        Ok(serialize(&BlockedBloomFilter::from_keys(
+            keys,
+            self.false_positive_rate,
+        )))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Box<dyn KeyFilter>> {
+        Ok(Box::new(deserialize::<BlockedBloomFilter>(bytes)?))
+    }
+}
+
+impl FilterPolicy for XorFilterPolicy {
+    fn name(&self) -> &'static str {
+        XOR
+    }
+
+    fn build(&self, keys: &[&str]) -> Result<Vec<u8>> {
+        Ok(serialize(&XorFilter::from_keys(keys)?))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Box<dyn KeyFilter>> {
+        Ok(Box::new(deserialize::<XorFilter>(bytes)?))
+    }
+}
+
+impl FilterPolicy for LegacyBloomFilterPolicy {
+    fn name(&self) -> &'static str {
+        LEGACY_BLOOM
+    }
+
+    fn build(&self, _keys: &[&str]) -> Result<Vec<u8>> {
+        Err(LsmError::SsTable(
+            "legacy bloom filters are only read".to_owned(),
+        ))
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Box<dyn KeyFilter>> {
+        Ok(Box::new(BloomFilter::decode(bytes, true)?))
+    }
+}
+
+pub fn from_name(name: &str) -> Result<Box<dyn FilterPolicy>> {
+    let false_positive_rate = Config::global().cache.bloom_filter_false_positive_rate;
+
+    match name {
+        BLOOM => Ok(Box::new(BloomFilterPolicy {
+            false_positive_rate,
+        })),
+        BLOCKED_BLOOM => Ok(Box::new(BlockedBloomFilterPolicy {
+            false_positive_rate,
+        })),
+        XOR => Ok(Box::new(XorFilterPolicy {})),
+        LEGACY_BLOOM => Ok(Box::new(LegacyBloomFilterPolicy {})),
+        _ => Err(LsmError::SsTable(format!("unknown filter policy {}", name))),
+    }
+}
+
+/*
+ * Policies new ss_tables can be written with, legacy bloom filters are only
+ * ever read
+ */
+pub fn for_writing(name: &str) -> Result<Box<dyn FilterPolicy>> {
+    if name == LEGACY_BLOOM {
+        return Err(LsmError::SsTable(format!(
+            "filter policy {} can only be read",
+            name
+        )));
+    }
+
+    from_name(name)
+}
+
+pub fn configured() -> Result<Box<dyn FilterPolicy>> {
+    for_writing(&Config::global().cache.filter_policy)
+}
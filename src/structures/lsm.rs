@@ -4,53 +4,85 @@ use crate::{
     config::Config,
     error::{LsmError, Result},
     structures::{
+        blob_manager::BlobManager,
+        block_cache::BlockCacheStats,
         cache::Cache,
-        compaction_manager::CompactionManager,
-        manifest::Manifest,
+        compaction_filter::CompactionFilter,
+        compaction_manager::{CompactionManager, CompactionStats},
+        compaction_strategy::LevelScore,
+        manifest::{Manifest, SSTableBasicInfo},
         memtable::MemTable,
-        ss_table_manager::SSTableManager,
+        ss_table_manager::{SSTableManager, SSTableValue},
+        table_properties::TableProperties,
         write_ahead_logger::{self, Operations, WriteAheadLogger},
     },
 };
 
+use std::collections::BTreeMap;
+
 use log::info;
+use tokio::task::JoinHandle;
 
 #[derive(Debug)]
 pub struct Lsm {
     memtable: Option<MemTable>,
-    immutable_memtable: Option<Arc<MemTable>>,
+    // memtables waiting to be flushed, oldest first
+    immutable_memtables: Arc<RwLock<Vec<Arc<MemTable>>>>,
     cache: Arc<RwLock<Cache>>,
     manifest: Arc<RwLock<Manifest>>,
+    compaction_manager: Arc<CompactionManager>,
+    flushes: Vec<JoinHandle<Result<()>>>,
 }
 
 impl Default for Lsm {
     fn default() -> Self {
+        Lsm::open(None)
+    }
+}
+
+impl Lsm {
+    /*
+     * The filter is called for every entry compaction merges, from the
+     * first compaction on
+     */
+    pub fn with_compaction_filter(filter: Box<dyn CompactionFilter>) -> Self {
+        Lsm::open(Some(filter))
+    }
+
+    fn open(compaction_filter: Option<Box<dyn CompactionFilter>>) -> Self {
         let memtable = WriteAheadLogger::read_from_file();
         let manifest = Arc::new(RwLock::new(Manifest::new()));
         let cache = Arc::new(RwLock::new(Cache::new()));
 
-        let cache_for_move = Arc::clone(&cache);
-        let manifest_for_move = Arc::clone(&manifest);
-        tokio::spawn(async move {
-            let compaction_manager = CompactionManager::new(manifest_for_move, cache_for_move);
-            compaction_manager.monitor().await;
-        });
+        let mut compaction_manager =
+            CompactionManager::new(Arc::clone(&manifest), Arc::clone(&cache))
+                .expect("Unable to create the compaction strategy");
+        if let Some(filter) = compaction_filter {
+            compaction_manager = compaction_manager.with_filter(filter);
+        }
+        let compaction_manager = Arc::new(compaction_manager);
+
+        compaction_manager.schedule();
+        tokio::spawn(CompactionManager::monitor(Arc::downgrade(
+            &compaction_manager,
+        )));
 
         Self {
             memtable: Some(memtable),
-            immutable_memtable: None,
+            immutable_memtables: Arc::new(RwLock::new(Vec::new())),
             cache,
             manifest,
+            compaction_manager,
+            flushes: Vec::new(),
         }
     }
-}
 
-impl Lsm {
     pub fn add(&mut self, key: &str, value: &str) -> Result<()> {
         info!("Adding an element with key:{} and value:{}", key, value);
 
         WriteAheadLogger::write(Operations::Put, key, value)
             .ok_or(LsmError::Wal("Failed to write to WAL".to_string()))?;
+        self.cache.read().unwrap().row_cache().invalidate(key);
 
         if self
             .memtable
@@ -59,14 +91,44 @@ impl Lsm {
             .len()
             >= Config::global().memtable.max_entries
         {
-            self.memtable_to_sstable();
+            self.compaction_manager.stall_writes();
+            let mem = self.memtable_to_sstable();
 
-            let mem = Arc::clone(self.immutable_memtable.as_ref().unwrap());
+            let immutable_memtables = Arc::clone(&self.immutable_memtables);
             let cache = Arc::clone(&self.cache);
             let manifest = Arc::clone(&self.manifest);
-            tokio::task::spawn_blocking(move || {
-                Lsm::persist_immutable_memtable(mem, cache, manifest)
-            });
+            let compaction_manager = Arc::clone(&self.compaction_manager);
+            self.flushes.retain(|flush| !flush.is_finished());
+
+            // the newer memtable has to get the higher table id, so flushes run one after the other
+            let previous = self.flushes.pop();
+            self.flushes.push(tokio::spawn(async move {
+                let previous = match previous {
+                    Some(previous) => previous
+                        .await
+                        .map_err(|e| LsmError::SsTable(e.to_string()))
+                        .and_then(|result| result),
+                    None => Ok(()),
+                };
+
+                let result = tokio::task::spawn_blocking(move || {
+                    let result = Lsm::persist_immutable_memtable(Arc::clone(&mem), cache, manifest);
+                    // the rows are read from the new ss_table from now on
+                    if result.is_ok() {
+                        immutable_memtables
+                            .write()
+                            .unwrap()
+                            .retain(|memtable| !Arc::ptr_eq(memtable, &mem));
+                    }
+                    compaction_manager.schedule();
+                    result
+                })
+                .await
+                .map_err(|e| LsmError::SsTable(e.to_string()))
+                .and_then(|result| result);
+
+                previous.and(result)
+            }));
         }
 
         self.memtable
@@ -77,44 +139,131 @@ impl Lsm {
         Ok(())
     }
 
-    pub fn get(&self, key: &str) -> Option<String> {
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
         if let Some(value) = self.memtable.as_ref().and_then(|m: &MemTable| m.get(key)) {
             info!("value found in memtable key: {} value {:?}", key, value);
-            return value.clone();
+            return Ok(value.clone());
         }
 
-        if let Some(value) = self.immutable_memtable.as_ref().and_then(|m| m.get(key)) {
+        if let Some(value) = self
+            .immutable_memtables
+            .read()
+            .unwrap()
+            .iter()
+            .rev()
+            .find_map(|m| m.get(key))
+        {
             info!(
-                "value found in immutable_memtable key: {} value {:?}",
+                "value found in immutable_memtables key: {} value {:?}",
                 key, value
             );
 
-            return value.clone();
+            return Ok(value.clone());
         }
 
-        info!("key {} not found in memtable or immutable_memtable", key);
+        info!("key {} not found in memtable or immutable_memtables", key);
 
+        let manifest = self.manifest.read().unwrap();
         let cache = self.cache.read().unwrap();
-        let mut files = cache.get(key).clone();
 
-        info!("files found containsing the key {:?}", files);
+        if let Some(value) = cache.row_cache().get(key) {
+            info!("value found in row cache key: {} value {:?}", key, value);
+            return Ok(value);
+        }
 
-        files.sort();
-        files.reverse();
+        let value = Lsm::get_from_ss_tables(&manifest, &cache, key)?;
+        cache.row_cache().insert(key, value.clone());
+
+        Ok(value)
+    }
+
+    fn get_from_ss_tables(manifest: &Manifest, cache: &Cache, key: &str) -> Result<Option<String>> {
+        // the first table holding the key has the latest value, which might be a thombstone
+        for ss_table in manifest.candidates(key) {
+            let file_name = ss_table.path.as_str();
+            if !cache.may_contain(file_name, key)? {
+                continue;
+            }
 
-        files.iter().find_map(|file_name| {
             info!(
-                "Value found in cache, retrieve from ss_table file_name: {}",
+                "Value might be in ss_table, retrieve from file_name: {}",
                 file_name
             );
 
-            let seek = cache.seek_position(file_name, key)?;
+            let Some(seek) = cache.seek_position(file_name, key)? else {
+                continue;
+            };
 
-            SSTableManager::read_from_file(file_name, seek)
-                .into_iter()
+            if let Some((_, value)) = cache
+                .data_block(file_name, &seek)?
+                .iter()
                 .find(|(k, _)| k == key)
-                .and_then(|(_, v)| v.clone())
-        })
+            {
+                return BlobManager::resolve(value.as_ref());
+            }
+        }
+
+        Ok(None)
+    }
+
+    /*
+     * Every live key in [start, end) with its value, in key order. Sources are
+     * merged from oldest to newest so newer values and thombstones win.
+     */
+    pub fn scan(&self, start: &str, end: &str) -> Result<Vec<(String, String)>> {
+        info!("scanning keys from {} to {}", start, end);
+
+        if start >= end {
+            return Ok(Vec::new());
+        }
+
+        let mut result: BTreeMap<String, Option<String>> = {
+            let manifest = self.manifest.read().unwrap();
+            let cache = self.cache.read().unwrap();
+
+            let mut merged: BTreeMap<String, Option<SSTableValue>> = BTreeMap::new();
+            for ss_table in manifest.candidates_in_range(start, end).into_iter().rev() {
+                let file_name = ss_table.path.as_str();
+                if !cache.may_contain_range(file_name, start, end)? {
+                    continue;
+                }
+
+                info!("reading range from ss_table {}", file_name);
+
+                for seek in cache.blocks_in_range(file_name, start, end)? {
+                    let entries = cache.data_block(file_name, &seek)?;
+                    for (key, value) in entries.iter() {
+                        if key.as_str() >= start && key.as_str() < end {
+                            merged.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+            }
+
+            // blob files are deleted under the manifest lock, so they are read before it is released
+            let mut resolved = BTreeMap::new();
+            for (key, value) in merged {
+                let value = BlobManager::resolve(value.as_ref())?;
+                resolved.insert(key, value);
+            }
+
+            resolved
+        };
+
+        let immutable_memtables = self.immutable_memtables.read().unwrap();
+        let memtables = immutable_memtables.iter().map(Arc::as_ref);
+        for memtable in memtables.chain(self.memtable.as_ref()) {
+            for (key, value) in memtable.tree.range(start.to_owned()..end.to_owned()) {
+                result.insert(key.clone(), value.clone());
+            }
+        }
+
+        let live = result
+            .into_iter()
+            .filter_map(|(key, value)| value.map(|value| (key, value)))
+            .collect();
+
+        Ok(live)
     }
 
     /*
@@ -125,6 +274,7 @@ impl Lsm {
 
         WriteAheadLogger::write(Operations::Delete, key, "")
             .ok_or(LsmError::Wal("Failed to write to WAL".to_string()))?;
+        self.cache.read().unwrap().row_cache().invalidate(key);
 
         self.memtable
             .as_mut()
@@ -134,12 +284,101 @@ impl Lsm {
         Ok(())
     }
 
-    fn memtable_to_sstable(&mut self) {
+    /*
+     * Properties of every live ss_table, files without a properties block are skipped
+     */
+    pub fn table_properties(&self) -> Result<Vec<(SSTableBasicInfo, TableProperties)>> {
+        let ss_tables = self.manifest.read().unwrap().ss_tables().to_vec();
+        let cache = self.cache.read().unwrap();
+
+        let mut result = Vec::new();
+        for ss_table in ss_tables {
+            match SSTableManager::read_properties(&cache, &ss_table.path)? {
+                Some(properties) => result.push((ss_table, properties)),
+                None => info!("no properties block in ss_table {}", ss_table.path),
+            }
+        }
+
+        Ok(result)
+    }
+
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.cache.read().unwrap().block_cache_stats()
+    }
+
+    /*
+     * Compacts the ss_tables overlapping [start, end] into the target level,
+     * L1 or below, right away and returns once done
+     */
+    pub fn compact_range(&self, start: &str, end: &str, target_level: usize) -> Result<()> {
+        info!(
+            "compacting keys from {} to {} into level {}",
+            start, end, target_level
+        );
+
+        self.compaction_manager
+            .compact_range(start, end, target_level)
+    }
+
+    pub fn compaction_stats(&self) -> CompactionStats {
+        self.compaction_manager.stats()
+    }
+
+    pub fn compaction_scores(&self) -> Vec<LevelScore> {
+        self.compaction_manager.scores()
+    }
+
+    /*
+     * Waits for the flushes started so far and the compactions they lead
+     * to, returns the error of a failed flush
+     */
+    pub async fn wait_for_background_work(&mut self) -> Result<()> {
+        for flush in self.flushes.drain(..) {
+            flush
+                .await
+                .map_err(|e| LsmError::SsTable(e.to_string()))??;
+        }
+
+        let compaction_manager = Arc::clone(&self.compaction_manager);
+        tokio::task::spawn_blocking(move || compaction_manager.wait_for_compactions())
+            .await
+            .map_err(|e| LsmError::SsTable(e.to_string()))
+    }
+
+    /*
+     * Limits the bytes flushes and compactions read and write per second,
+     * 0 is no limit. Applies to I/O already waiting.
+     */
+    pub fn set_io_rate_limit(&self, bytes_per_second: u64) {
+        info!(
+            "limiting background I/O to {} bytes per second",
+            bytes_per_second
+        );
+
+        self.cache
+            .read()
+            .unwrap()
+            .rate_limiter()
+            .set_bytes_per_second(bytes_per_second);
+    }
+
+    fn memtable_to_sstable(&mut self) -> Arc<MemTable> {
         info!("persisting the memtable to file");
 
-        self.immutable_memtable = Some(Arc::new(self.memtable.take().unwrap()));
-        self.memtable = Some(MemTable::default());
-        write_ahead_logger::increment_index();
+        let mut memtable = self.memtable.take().unwrap();
+        memtable.wal_index = write_ahead_logger::increment_index();
+
+        let memtable = Arc::new(memtable);
+        self.immutable_memtables
+            .write()
+            .unwrap()
+            .push(Arc::clone(&memtable));
+        self.memtable = Some(MemTable {
+            wal_index: write_ahead_logger::index(),
+            ..Default::default()
+        });
+
+        memtable
     }
 
     fn persist_immutable_memtable(
@@ -1,18 +1,45 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     fs::{self, OpenOptions},
     io::Write,
+    path::Path,
 };
 
-use log::trace;
+use log::{trace, warn};
 use serde::{Deserialize, Serialize};
 
-use crate::config::Config;
+use crate::{
+    config::Config,
+    structures::{blob_manager::blob_file_path, compaction_strategy::LEVELED},
+};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Manifest {
     version: usize,
     next_id: usize,
     ss_tables: Vec<SSTableBasicInfo>,
+    #[serde(default)]
+    blob_files: Vec<BlobFileInfo>,
+    #[serde(default)]
+    compact_pointers: BTreeMap<usize, String>,
+    #[serde(default = "default_compaction_style")]
+    compaction_style: String,
+}
+
+/*
+ * Manifests written before the style was recorded belong to leveled databases
+ */
+fn default_compaction_style() -> String {
+    LEVELED.to_owned()
+}
+
+fn ss_table_path(level: usize, id: usize) -> String {
+    format!(
+        "{}/L{}_{:010}.sst",
+        Config::global().directory.ss_table,
+        level,
+        id
+    )
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,17 +49,75 @@ pub struct SSTableBasicInfo {
     pub level: usize,
     pub min_key: String,
     pub max_key: String,
+    #[serde(default)]
+    pub file_size: u64,
+    #[serde(default)]
+    pub creation_time: u64,
 }
 
 impl SSTableBasicInfo {
-    pub fn new(id: usize, path: String, level: usize, min_key: String, max_key: String) -> Self {
+    pub fn new(
+        id: usize,
+        path: String,
+        level: usize,
+        min_key: String,
+        max_key: String,
+        file_size: u64,
+        creation_time: u64,
+    ) -> Self {
         Self {
             id,
             path,
             level,
             min_key,
             max_key,
+            file_size,
+            creation_time,
+        }
+    }
+
+    /*
+     * Tables written before the key range was recorded have empty keys
+     * and can not be excluded
+     */
+    pub fn has_key_range(&self) -> bool {
+        !self.min_key.is_empty() || !self.max_key.is_empty()
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        !self.has_key_range() || (self.min_key.as_str() <= key && key <= self.max_key.as_str())
+    }
+
+    pub fn overlaps(&self, min_key: &str, max_key: &str) -> bool {
+        !self.has_key_range()
+            || (self.min_key.as_str() <= max_key && min_key <= self.max_key.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BlobFileInfo {
+    pub id: usize,
+    pub path: String,
+    pub total_bytes: u64,
+    pub garbage_bytes: u64,
+}
+
+impl BlobFileInfo {
+    pub fn new(id: usize, path: String) -> Self {
+        Self {
+            id,
+            path,
+            total_bytes: 0,
+            garbage_bytes: 0,
+        }
+    }
+
+    pub fn garbage_ratio(&self) -> f64 {
+        if self.total_bytes == 0 {
+            return 0.0;
         }
+
+        self.garbage_bytes as f64 / self.total_bytes as f64
     }
 }
 
@@ -42,41 +127,307 @@ impl Default for Manifest {
             version: 1,
             next_id: 1,
             ss_tables: Vec::new(),
+            blob_files: Vec::new(),
+            compact_pointers: BTreeMap::new(),
+            compaction_style: Config::global().ss_table.compaction_style.clone(),
         }
     }
 }
 
 impl Manifest {
-    pub fn create_filename(&mut self, level: usize) -> (String, usize) {
+    /*
+     * Ids are persisted as soon as they are handed out, so an id is never
+     * reused after a restart, not even one of a file that was never listed.
+     * Blocks cached under an id, also in the secondary cache, always belong
+     * to the same file.
+     */
+    fn next_id(&mut self) -> usize {
         let id = self.next_id;
         self.next_id += 1;
+        self.persist();
+
+        id
+    }
 
-        let path = format!(
-            "{}/L{}_{:010}.sst",
-            Config::global().directory.ss_table,
+    pub fn create_filename(&mut self, level: usize) -> (String, usize) {
+        let id = self.next_id();
+
+        (ss_table_path(level, id), id)
+    }
+
+    /*
+     * Assigns the table to another level without rewriting it. The file is
+     * linked under the name of the new level before the manifest is
+     * persisted, so the manifest never points to a missing file. The old
+     * file is left to the caller, readers may still have it open.
+     */
+    pub fn move_table(
+        &mut self,
+        ss_table: &SSTableBasicInfo,
+        level: usize,
+    ) -> std::io::Result<SSTableBasicInfo> {
+        let path = ss_table_path(level, ss_table.id);
+        fs::hard_link(&ss_table.path, &path)?;
+
+        let moved = SSTableBasicInfo {
+            path,
             level,
-            id
-        );
+            ..ss_table.clone()
+        };
+
+        self.ss_tables.retain(|t| t.id != ss_table.id);
+        self.ss_tables.push(moved.clone());
+        self.persist();
 
-        (path, id)
+        Ok(moved)
     }
 
-    pub fn remove(&mut self, table: &SSTableBasicInfo) {
-        self.ss_tables.retain(|t| t.id != table.id);
+    pub fn create_blob_filename(&mut self) -> (String, usize) {
+        let id = self.next_id();
+
+        (blob_file_path(id), id)
     }
 
-    pub fn compaction_nominees(&mut self, level: usize) -> Vec<SSTableBasicInfo> {
-        let mut result = self.ss_tables_in_level(0);
+    pub fn add_blob_file(&mut self, blob_file: BlobFileInfo) {
+        self.blob_files.push(blob_file);
+        self.persist();
+    }
 
-        result.sort_by_key(|t| t.id);
+    pub fn blob_file(&self, id: usize) -> Option<&BlobFileInfo> {
+        self.blob_files.iter().find(|b| b.id == id)
+    }
 
-        trace!("{} ss_tables found for level: {}", result.len(), level);
+    /*
+     * Records bytes of a blob file that are no longer referenced by any ss_table
+     */
+    pub fn add_blob_garbage(&mut self, id: usize, bytes: u64) {
+        if let Some(blob_file) = self.blob_files.iter_mut().find(|b| b.id == id) {
+            blob_file.garbage_bytes += bytes;
+        }
+    }
 
-        result
+    /*
+     * Removes the blob files without any live value left and returns them,
+     * so the caller can delete them from disk
+     */
+    pub fn take_obsolete_blob_files(&mut self) -> Vec<BlobFileInfo> {
+        let (obsolete, live): (Vec<BlobFileInfo>, Vec<BlobFileInfo>) = self
+            .blob_files
+            .drain(..)
+            .partition(|b| b.garbage_bytes >= b.total_bytes);
+
+        self.blob_files = live;
+        self.persist();
+
+        trace!("{} obsolete blob files found", obsolete.len());
+
+        obsolete
+    }
+
+    /*
+     * Swaps the inputs of a compaction for its outputs in a single write of
+     * the manifest, which is replaced as a whole, so a crash never leaves
+     * both or neither of them live. The blob files the outputs point into
+     * are added in the same write.
+     */
+    pub fn replace(
+        &mut self,
+        inputs: &[SSTableBasicInfo],
+        outputs: Vec<SSTableBasicInfo>,
+        blob_files: Vec<BlobFileInfo>,
+    ) {
+        self.ss_tables
+            .retain(|t| !inputs.iter().any(|input| input.id == t.id));
+        self.ss_tables.extend(outputs);
+        self.blob_files.extend(blob_files);
+        self.persist();
+    }
+
+    /*
+     * Returns the tables to compact out of the level together with every
+     * table of the next level overlapping their key range, ordered from the
+     * oldest to the newest. L0 tables overlap each other, so all of them are
+     * compacted at once, from the other levels a single table is picked.
+     * Tables being compacted already are busy, nothing is picked which
+     * would have to include one of them.
+     */
+    pub fn compaction_nominees(
+        &mut self,
+        level: usize,
+        busy: &BTreeSet<usize>,
+    ) -> Vec<SSTableBasicInfo> {
+        let nominees = match level {
+            0 => {
+                let mut result = self
+                    .ss_tables_in_level(0)
+                    .into_iter()
+                    .filter(|t| !busy.contains(&t.id))
+                    .cloned()
+                    .collect::<Vec<SSTableBasicInfo>>();
+                result.sort_by_key(|t| t.id);
+                result
+            }
+            _ => self.pick_table(level, busy).into_iter().collect(),
+        };
+
+        trace!("{} ss_tables picked for level: {}", nominees.len(), level);
+
+        if nominees.is_empty() {
+            return nominees;
+        }
+
+        let mut overlapping = self.overlapping(&nominees, level + 1);
+
+        trace!(
+            "{} overlapping ss_tables found for level: {}",
+            overlapping.len(),
+            level + 1
+        );
+
+        if overlapping.iter().any(|t| busy.contains(&t.id)) {
+            trace!("overlapping ss_tables of level {} are busy", level + 1);
+            return Vec::new();
+        }
+
+        overlapping.sort_by_key(|t| t.id);
+        overlapping.extend(nominees);
+
+        overlapping
+    }
+
+    fn overlapping(&self, tables: &[SSTableBasicInfo], level: usize) -> Vec<SSTableBasicInfo> {
+        let unbounded = tables.iter().any(|t| !t.has_key_range());
+        let min_key = tables
+            .iter()
+            .map(|t| t.min_key.as_str())
+            .min()
+            .unwrap_or("");
+        let max_key = tables
+            .iter()
+            .map(|t| t.max_key.as_str())
+            .max()
+            .unwrap_or("");
+
+        self.ss_tables_in_level(level)
             .into_iter()
-            .take(2)
+            .filter(|t| unbounded || t.overlaps(min_key, max_key))
             .cloned()
-            .collect::<Vec<SSTableBasicInfo>>()
+            .collect()
+    }
+
+    /*
+     * Tables of a level are picked in key order, starting after the largest
+     * key of the previous pick and wrapping around, so every part of the
+     * key space gets compacted in turn. Tables which are busy or overlap a
+     * busy table of the next level are passed over.
+     */
+    fn pick_table(&mut self, level: usize, busy: &BTreeSet<usize>) -> Option<SSTableBasicInfo> {
+        let mut tables = self.ss_tables_in_level(level);
+        tables.sort_by(|a, b| a.min_key.cmp(&b.min_key));
+
+        let pointer = self.compact_pointers.get(&level).map_or("", String::as_str);
+        let start = tables
+            .iter()
+            .position(|t| t.min_key.as_str() > pointer)
+            .unwrap_or(0);
+
+        let picked = tables[start..]
+            .iter()
+            .chain(tables[..start].iter())
+            .find(|t| {
+                !busy.contains(&t.id)
+                    && self
+                        .overlapping(std::slice::from_ref(*t), level + 1)
+                        .iter()
+                        .all(|o| !busy.contains(&o.id))
+            })
+            .map(|t| (*t).clone())?;
+
+        self.compact_pointers.insert(level, picked.max_key.clone());
+        self.persist();
+
+        Some(picked)
+    }
+
+    /*
+     * Tables down to the target level overlapping [start, end], the range
+     * grows to the key range of the tables found until no other table of
+     * these levels overlaps it. Ordered from the oldest to the newest.
+     */
+    pub fn range_nominees(
+        &self,
+        start: &str,
+        end: &str,
+        target_level: usize,
+    ) -> Vec<SSTableBasicInfo> {
+        let (mut min_key, mut max_key) = (start.to_owned(), end.to_owned());
+
+        loop {
+            let mut result = self
+                .ss_tables
+                .iter()
+                .filter(|t| t.level <= target_level && t.overlaps(&min_key, &max_key))
+                .cloned()
+                .collect::<Vec<SSTableBasicInfo>>();
+
+            let bounded = result.iter().filter(|t| t.has_key_range());
+            let grown_min = bounded.clone().map(|t| &t.min_key).min();
+            let grown_max = bounded.map(|t| &t.max_key).max();
+
+            let mut grown = false;
+            if let Some(grown_min) = grown_min.filter(|k| **k < min_key) {
+                min_key = grown_min.clone();
+                grown = true;
+            }
+            if let Some(grown_max) = grown_max.filter(|k| **k > max_key) {
+                max_key = grown_max.clone();
+                grown = true;
+            }
+
+            if !grown {
+                result.sort_by_key(|t| (std::cmp::Reverse(t.level), t.id));
+                return result;
+            }
+        }
+    }
+
+    pub fn level_size(&self, level: usize) -> u64 {
+        self.ss_tables
+            .iter()
+            .filter(|t| t.level == level)
+            .map(|t| t.file_size)
+            .sum()
+    }
+
+    /*
+     * Tables which could hold the key in the order they have to be searched,
+     * newest L0 tables first and then level by level
+     */
+    pub fn candidates(&self, key: &str) -> Vec<&SSTableBasicInfo> {
+        let mut result = self
+            .ss_tables
+            .iter()
+            .filter(|t| t.may_contain(key))
+            .collect::<Vec<&SSTableBasicInfo>>();
+
+        result.sort_by_key(|t| (t.level, std::cmp::Reverse(t.id)));
+
+        result
+    }
+
+    /*
+     * Tables which could hold keys in [start, end), in the same order as candidates
+     */
+    pub fn candidates_in_range(&self, start: &str, end: &str) -> Vec<&SSTableBasicInfo> {
+        let mut result = self
+            .ss_tables
+            .iter()
+            .filter(|t| t.overlaps(start, end))
+            .collect::<Vec<&SSTableBasicInfo>>();
+
+        result.sort_by_key(|t| (t.level, std::cmp::Reverse(t.id)));
+
+        result
     }
 
     pub fn add(&mut self, ss_table: SSTableBasicInfo) {
@@ -84,6 +435,10 @@ impl Manifest {
         self.persist();
     }
 
+    pub fn ss_tables(&self) -> &[SSTableBasicInfo] {
+        &self.ss_tables
+    }
+
     pub fn ss_tables_in_level(&self, level: usize) -> Vec<&SSTableBasicInfo> {
         trace!("searching ss_tables for level: {}", level);
 
@@ -106,29 +461,76 @@ impl Manifest {
         Ok(result)
     }
 
+    /*
+     * The manifest is written to a temporary file which then replaces it,
+     * so a crash leaves either the old or the new manifest behind
+     */
     pub fn persist(&mut self) {
         let file_path = &Config::global().ss_table.manifest_location;
+        let temporary_path = format!("{}.tmp", file_path);
+
         let mut file = OpenOptions::new()
-            .read(true)
             .create(true)
             .write(true)
-            .truncate(false)
-            .open(file_path)
-            .expect("Unable to create or open the manifest file");
+            .truncate(true)
+            .open(&temporary_path)
+            .expect("Unable to create the temporary manifest file");
 
         let serde_bytes = serde_json::to_string_pretty(self).expect("Unable to serde the Manifest");
         file.write_all(serde_bytes.as_bytes())
             .expect("Unable to write to Manifest file");
+        file.sync_all().expect("Unable to sync the Manifest file");
+
+        fs::rename(&temporary_path, file_path).expect("Unable to replace the Manifest file");
+
+        // the rename is only durable once the directory is synced
+        let directory = match Path::new(file_path).parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        if let Err(e) = fs::File::open(directory).and_then(|d| d.sync_all()) {
+            warn!("Unable to sync the directory of the Manifest file: {}", e);
+        }
+    }
+
+    pub fn compaction_style(&self) -> &str {
+        &self.compaction_style
+    }
+
+    /*
+     * The levels of a database are laid out for the style it was created
+     * with, the configured style is only taken over while there are no tables
+     */
+    fn apply_compaction_style(&mut self) {
+        let configured = &Config::global().ss_table.compaction_style;
+        if *configured == self.compaction_style {
+            return;
+        }
+
+        if !self.ss_tables.is_empty() {
+            warn!(
+                "The database uses the {} compaction style, ignoring the configured {}",
+                self.compaction_style, configured
+            );
+            return;
+        }
+
+        self.compaction_style = configured.clone();
+        self.persist();
     }
 
     pub fn new() -> Self {
         let from_file = Manifest::read_from_file();
-        from_file.unwrap_or_else(|_| {
+        let mut result = from_file.unwrap_or_else(|_| {
             let mut result = Manifest::default();
 
             result.persist();
 
             result
-        })
+        });
+
+        result.apply_compaction_style();
+
+        result
     }
 }
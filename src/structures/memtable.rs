@@ -1,11 +1,9 @@
 use std::collections::BTreeMap;
 
-use crate::structures::bloom_filter::BloomFilter;
-
 #[derive(Debug, Default)]
 pub struct MemTable {
     pub tree: BTreeMap<String, Option<String>>,
-    pub bloom_filter: BloomFilter,
+    pub wal_index: usize,
 }
 
 impl MemTable {
@@ -14,7 +12,6 @@ impl MemTable {
     }
 
     pub fn add(&mut self, key: &str, value: &str) {
-        self.bloom_filter.update(key);
         self.tree.insert(key.to_owned(), Some(value.to_owned()));
     }
 
@@ -0,0 +1,126 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+
+use crate::error::{LsmError, Result};
+use crate::structures::ss_table_manager::SSTableValue;
+
+type Entry = (String, Option<SSTableValue>);
+
+/*
+ * The newest version of a key together with the older versions it shadows
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedEntry {
+    pub key: String,
+    pub value: Option<SSTableValue>,
+    pub shadowed: Vec<Option<SSTableValue>>,
+}
+
+/*
+ * Head of one input, ordered by key and for equal keys by the rank of the
+ * input, the newest input ranks highest
+ */
+#[derive(Debug)]
+struct Head {
+    key: String,
+    value: Option<SSTableValue>,
+    rank: usize,
+}
+
+impl PartialEq for Head {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Head {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .cmp(&other.key)
+            .then_with(|| other.rank.cmp(&self.rank))
+    }
+}
+
+/*
+ * Merges any number of sorted inputs into one sorted stream, keeping only
+ * the head of every input in memory. Inputs are passed from the oldest to
+ * the newest, for a key found in several inputs the newest version wins.
+ */
+#[derive(Debug)]
+pub struct MergeIterator<I> {
+    inputs: Vec<I>,
+    heap: BinaryHeap<Reverse<Head>>,
+    error: Option<LsmError>,
+}
+
+impl<I: Iterator<Item = Result<Entry>>> MergeIterator<I> {
+    pub fn new(inputs: Vec<I>) -> Self {
+        let mut result = Self {
+            inputs,
+            heap: BinaryHeap::new(),
+            error: None,
+        };
+
+        for rank in 0..result.inputs.len() {
+            result.advance(rank);
+        }
+
+        result
+    }
+
+    /*
+     * Pushes the next entry of the input onto the heap, a failing input
+     * ends the merge with its error
+     */
+    fn advance(&mut self, rank: usize) {
+        match self.inputs[rank].next() {
+            Some(Ok((key, value))) => self.heap.push(Reverse(Head { key, value, rank })),
+            Some(Err(e)) => self.error = self.error.take().or(Some(e)),
+            None => (),
+        }
+    }
+}
+
+impl<I: Iterator<Item = Result<Entry>>> Iterator for MergeIterator<I> {
+    type Item = Result<MergedEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(e) = self.error.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+
+        let Reverse(newest) = self.heap.pop()?;
+        self.advance(newest.rank);
+
+        let mut shadowed = Vec::new();
+        while let Some(Reverse(head)) = self.heap.peek() {
+            if head.key != newest.key {
+                break;
+            }
+
+            let Reverse(older) = self.heap.pop().expect("the heap has a head");
+            self.advance(older.rank);
+            shadowed.push(older.value);
+        }
+
+        if let Some(e) = self.error.take() {
+            self.heap.clear();
+            return Some(Err(e));
+        }
+
+        Some(Ok(MergedEntry {
+            key: newest.key,
+            value: newest.value,
+            shadowed,
+        }))
+    }
+}
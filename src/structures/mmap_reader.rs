@@ -0,0 +1,51 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use log::info;
+use memmap2::Mmap;
+
+use crate::error::{LsmError, Result};
+
+/*
+ * Read only mappings of ss_tables keyed by file name. ss_tables are never
+ * modified once written, so a mapping stays valid until the file is removed.
+ */
+#[derive(Debug, Default)]
+pub struct MmapReader {
+    mappings: Mutex<BTreeMap<String, Arc<Mmap>>>,
+}
+
+impl MmapReader {
+    pub fn map(&self, file_name: &str) -> Result<Arc<Mmap>> {
+        let mut mappings = self.mappings.lock().unwrap();
+
+        if let Some(mmap) = mappings.get(file_name) {
+            return Ok(Arc::clone(mmap));
+        }
+
+        info!("mapping file {} into memory", file_name);
+
+        let file = File::open(file_name)?;
+        // SAFETY: ss_tables are immutable after they are written and only removed after release
+        let mmap = Arc::new(unsafe { Mmap::map(&file)? });
+
+        mappings.insert(file_name.to_owned(), Arc::clone(&mmap));
+
+        Ok(mmap)
+    }
+
+    pub fn block(mmap: &Mmap, offset: u64, size: u64) -> Result<&[u8]> {
+        let start = offset as usize;
+
+        mmap.get(start..start + size as usize)
+            .ok_or(LsmError::SsTable(format!(
+                "block at offset {} and size {} is out of bounds",
+                offset, size
+            )))
+    }
+
+    pub fn release(&self, file_name: &str) {
+        self.mappings.lock().unwrap().remove(file_name);
+    }
+}
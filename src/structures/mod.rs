@@ -1,8 +1,25 @@
+pub mod blob_manager;
+pub mod block_cache;
+pub mod blocked_bloom_filter;
 pub mod bloom_filter;
 pub mod cache;
+pub mod compaction_filter;
 pub mod compaction_manager;
+pub mod compaction_strategy;
+pub mod direct_io;
+pub mod filter_policy;
 pub mod lsm;
 pub mod manifest;
 pub mod memtable;
+pub mod merge_iterator;
+pub mod mmap_reader;
+pub mod range_filter;
+pub mod rate_limiter;
+pub mod row_cache;
+pub mod secondary_cache;
 pub mod ss_table_manager;
+pub mod table_cache;
+pub mod table_properties;
+pub mod thread_pool;
 pub mod write_ahead_logger;
+pub mod xor_filter;
@@ -0,0 +1,94 @@
+use serde::{Deserialize, Serialize};
+
+use crate::structures::bloom_filter::BloomFilter;
+
+const MAX_PROBED_PREFIXES: u32 = 16;
+
+/*
+ * Prefix bloom hierarchy: every prefix of every key up to max_prefix_len
+ * characters is added to one bloom filter. All keys of a range share the
+ * common prefix of its bounds, and one more character lies between the
+ * characters of the bounds at that position. If none of these prefixes
+ * is in the filter, the table holds no key of the range.
+ */
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RangeFilter {
+    filter: BloomFilter,
+    max_prefix_len: usize,
+}
+
+fn prefixes(key: &str, max_prefix_len: usize) -> impl Iterator<Item = &str> {
+    key.char_indices()
+        .skip(1)
+        .map(|(index, _)| &key[..index])
+        .chain(std::iter::once(key))
+        .filter(|prefix| !prefix.is_empty())
+        .take(max_prefix_len)
+}
+
+impl RangeFilter {
+    pub fn from_keys(keys: &[&str], max_prefix_len: usize, false_positive_rate: f64) -> Self {
+        let prefixes = keys
+            .iter()
+            .flat_map(|key| prefixes(key, max_prefix_len))
+            .collect::<Vec<&str>>();
+
+        Self {
+            filter: BloomFilter::from_keys(&prefixes, false_positive_rate),
+            max_prefix_len,
+        }
+    }
+
+    /*
+     * Sized up front for every prefix of the expected number of keys, so the
+     * keys can be added one at a time while the table is written
+     */
+    pub fn new(expected_keys: usize, max_prefix_len: usize, false_positive_rate: f64) -> Self {
+        Self {
+            filter: BloomFilter::new(
+                expected_keys.saturating_mul(max_prefix_len),
+                false_positive_rate,
+            ),
+            max_prefix_len,
+        }
+    }
+
+    pub fn add(&mut self, key: &str) {
+        prefixes(key, self.max_prefix_len).for_each(|prefix| self.filter.update(prefix));
+    }
+
+    /*
+     * Whether a key in [start, end) might be in the table
+     */
+    pub fn may_contain_range(&self, start: &str, end: &str) -> bool {
+        if start >= end {
+            return false;
+        }
+
+        let common = start
+            .chars()
+            .zip(end.chars())
+            .take_while(|(s, e)| s == e)
+            .map(|(s, _)| s)
+            .collect::<String>();
+        let common_len = common.chars().count();
+
+        if common_len >= self.max_prefix_len {
+            let prefix = prefixes(&common, self.max_prefix_len).last().unwrap();
+            return self.filter.contains(prefix);
+        }
+
+        // end is always longer than the common prefix, start is not if it is a prefix of end
+        let (Some(first), Some(last)) =
+            (start.chars().nth(common_len), end.chars().nth(common_len))
+        else {
+            return common.is_empty() || self.filter.contains(&common);
+        };
+
+        if last as u32 - first as u32 >= MAX_PROBED_PREFIXES {
+            return common.is_empty() || self.filter.contains(&common);
+        }
+
+        (first..=last).any(|c| self.filter.contains(&format!("{}{}", common, c)))
+    }
+}
@@ -0,0 +1,123 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+use log::trace;
+
+use crate::config::Config;
+
+/*
+ * Flushes are High, since writes stall behind them, compaction is Low.
+ * Foreground reads never ask the limiter at all.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoPriority {
+    High,
+    Low,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    available: f64,
+    last_refill: Instant,
+    high_waiting: usize,
+}
+
+/*
+ * Token bucket shared by the background I/O of one database. It is refilled with
+ * bytes_per_second bytes every second and holds at most one second worth of
+ * them. A request larger than what is left puts the bucket in debt, which
+ * the following requests wait out. 0 bytes per second is no limit.
+ */
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes_per_second: AtomicU64,
+    bucket: Mutex<Bucket>,
+    refilled: Condvar,
+}
+
+impl Bucket {
+    fn refill(&mut self, bytes_per_second: u64) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.available =
+            (self.available + elapsed * bytes_per_second as f64).min(bytes_per_second as f64);
+        self.last_refill = now;
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        RateLimiter::new(Config::global().io.rate_limit_bytes_per_second)
+    }
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second: AtomicU64::new(bytes_per_second),
+            bucket: Mutex::new(Bucket {
+                available: bytes_per_second as f64,
+                last_refill: Instant::now(),
+                high_waiting: 0,
+            }),
+            refilled: Condvar::new(),
+        }
+    }
+
+    pub fn bytes_per_second(&self) -> u64 {
+        self.bytes_per_second.load(Ordering::Relaxed)
+    }
+
+    /*
+     * Takes effect for requests already waiting
+     */
+    pub fn set_bytes_per_second(&self, bytes_per_second: u64) {
+        self.bytes_per_second
+            .store(bytes_per_second, Ordering::Relaxed);
+        self.refilled.notify_all();
+    }
+
+    /*
+     * Blocks until the bytes may be read or written. Low requests wait as
+     * long as a High request is waiting.
+     */
+    pub fn request(&self, bytes: u64, priority: IoPriority) {
+        let mut bucket = self.bucket.lock().unwrap();
+
+        if priority == IoPriority::High {
+            bucket.high_waiting += 1;
+        }
+
+        loop {
+            let bytes_per_second = self.bytes_per_second();
+            if bytes_per_second == 0 {
+                break;
+            }
+
+            bucket.refill(bytes_per_second);
+
+            let yielding = priority == IoPriority::Low && bucket.high_waiting > 0;
+            if !yielding && bucket.available > 0.0 {
+                bucket.available -= bytes as f64;
+                break;
+            }
+
+            let missing = (-bucket.available).max(1.0);
+            let wait = Duration::from_secs_f64(missing / bytes_per_second as f64)
+                .min(Duration::from_millis(100));
+
+            trace!(
+                "waiting {:?} for {} bytes of {:?} I/O",
+                wait, bytes, priority
+            );
+            bucket = self.refilled.wait_timeout(bucket, wait).unwrap().0;
+        }
+
+        if priority == IoPriority::High {
+            bucket.high_waiting -= 1;
+            self.refilled.notify_all();
+        }
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use log::trace;
+
+use crate::config::Config;
+
+/*
+ * Results of point lookups served from ss_tables, None records that no
+ * ss_table holds a value for the key. Bounded by the bytes of keys and
+ * values, the least recently used row is evicted first.
+ */
+#[derive(Debug)]
+pub struct RowCache {
+    rows: Mutex<Rows>,
+    capacity: usize,
+}
+
+#[derive(Debug, Default)]
+struct Rows {
+    entries: BTreeMap<String, (Option<String>, u64)>,
+    lru: BTreeMap<u64, String>,
+    usage: usize,
+    tick: u64,
+}
+
+fn charge(key: &str, value: &Option<String>) -> usize {
+    key.len() + value.as_ref().map_or(0, String::len)
+}
+
+impl Rows {
+    fn remove(&mut self, key: &str) {
+        if let Some((value, last_used)) = self.entries.remove(key) {
+            self.lru.remove(&last_used);
+            self.usage -= charge(key, &value);
+        }
+    }
+}
+
+impl Default for RowCache {
+    fn default() -> Self {
+        RowCache::new(Config::global().cache.row_cache_size)
+    }
+}
+
+impl RowCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            rows: Mutex::new(Rows::default()),
+            capacity,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Option<String>> {
+        let mut rows = self.rows.lock().unwrap();
+        rows.tick += 1;
+        let tick = rows.tick;
+
+        let (value, last_used) = rows.entries.get_mut(key)?;
+        let value = value.clone();
+        let last_used = std::mem::replace(last_used, tick);
+
+        rows.lru.remove(&last_used);
+        rows.lru.insert(tick, key.to_owned());
+
+        Some(value)
+    }
+
+    /*
+     * Rows larger than the whole cache are not cached, a capacity of 0
+     * disables the cache
+     */
+    pub fn insert(&self, key: &str, value: Option<String>) {
+        if charge(key, &value) > self.capacity {
+            return;
+        }
+
+        let mut rows = self.rows.lock().unwrap();
+        rows.remove(key);
+
+        rows.tick += 1;
+        let tick = rows.tick;
+        rows.usage += charge(key, &value);
+        rows.entries.insert(key.to_owned(), (value, tick));
+        rows.lru.insert(tick, key.to_owned());
+
+        while rows.usage > self.capacity {
+            let Some((_, oldest)) = rows.lru.pop_first() else {
+                break;
+            };
+
+            trace!("evicting row {}", oldest);
+            if let Some((value, _)) = rows.entries.remove(&oldest) {
+                rows.usage -= charge(&oldest, &value);
+            }
+        }
+    }
+
+    pub fn invalidate(&self, key: &str) {
+        self.rows.lock().unwrap().remove(key);
+    }
+
+    pub fn clear(&self) {
+        *self.rows.lock().unwrap() = Rows::default();
+    }
+}
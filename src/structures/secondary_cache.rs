@@ -0,0 +1,291 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::io::SeekFrom;
+use std::io::prelude::*;
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{error, info, trace};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{LsmError, Result};
+use crate::structures::block_cache::BlockKey;
+
+const LENGTH_SIZE: u64 = 8;
+
+/*
+ * Blocks evicted from the block cache, appended to a single file on local
+ * storage. Every record carries its key and a checksum of the block, records
+ * which fail the checksum are dropped, on load and on every read. Once the
+ * file holds twice the capacity it is rewritten with the live records only.
+ */
+#[derive(Debug)]
+pub struct SecondaryCache {
+    path: String,
+    capacity: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    file: File,
+    file_len: u64,
+    index: BTreeMap<BlockKey, (u64, u64)>,
+    order: VecDeque<(BlockKey, u64)>,
+    usage: usize,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: BlockKey,
+    checksum: u64,
+    bytes: Vec<u8>,
+}
+
+fn checksum(bytes: &[u8]) -> u64 {
+    xxhash_rust::xxh3::xxh3_64(bytes)
+}
+
+fn open_file(path: &str) -> Result<File> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    Ok(OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?)
+}
+
+/*
+ * Returns the record at the offset and the offset of the next one, None if
+ * the file ends in the middle of a record
+ */
+fn read_record(file: &mut File, offset: u64) -> Result<Option<(Record, u64)>> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut length = [0u8; LENGTH_SIZE as usize];
+    if file.read_exact(&mut length).is_err() {
+        return Ok(None);
+    }
+
+    let length = u64::from_le_bytes(length);
+    let mut buffer = vec![0u8; length as usize];
+    if file.read_exact(&mut buffer).is_err() {
+        return Ok(None);
+    }
+
+    let record = bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))?;
+
+    Ok(Some((record, offset + LENGTH_SIZE + length)))
+}
+
+impl Inner {
+    fn remove(&mut self, key: &BlockKey) {
+        if let Some((_, length)) = self.index.remove(key) {
+            self.usage -= length as usize;
+        }
+    }
+
+    fn append(&mut self, record: &Record) -> Result<(u64, u64)> {
+        let bytes = bincode::serialize(record).map_err(|e| LsmError::SsTable(e.to_string()))?;
+        let offset = self.file_len;
+
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(&(bytes.len() as u64).to_le_bytes())?;
+        self.file.write_all(&bytes)?;
+
+        let length = LENGTH_SIZE + bytes.len() as u64;
+        self.file_len += length;
+
+        Ok((offset, length))
+    }
+
+    fn add(&mut self, key: BlockKey, offset: u64, length: u64) {
+        self.remove(&key);
+        self.index.insert(key, (offset, length));
+        self.order.push_back((key, offset));
+        self.usage += length as usize;
+    }
+
+    /*
+     * The order also holds records which were dropped or written again
+     * since, only the record the index points to is evicted
+     */
+    fn evict(&mut self, capacity: usize) {
+        while self.usage > capacity {
+            let Some((oldest, offset)) = self.order.pop_front() else {
+                break;
+            };
+
+            if self.index.get(&oldest).is_some_and(|(o, _)| *o == offset) {
+                trace!("evicting block {:?} from the secondary cache", oldest);
+                self.remove(&oldest);
+            }
+        }
+    }
+}
+
+impl SecondaryCache {
+    /*
+     * Loads the index of every valid record in the file, a torn record at
+     * the end of the file is cut off
+     */
+    pub fn open(path: &str, capacity: usize) -> Result<Self> {
+        info!("opening secondary cache {}", path);
+
+        let mut inner = Inner {
+            file: open_file(path)?,
+            file_len: 0,
+            index: BTreeMap::new(),
+            order: VecDeque::new(),
+            usage: 0,
+        };
+
+        loop {
+            let offset = inner.file_len;
+            let (record, next) = match read_record(&mut inner.file, offset) {
+                Ok(Some(result)) => result,
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Unreadable secondary cache record at {}: {}", offset, e);
+                    break;
+                }
+            };
+
+            inner.file_len = next;
+
+            if checksum(&record.bytes) != record.checksum {
+                error!(
+                    "Checksum mismatch of secondary cache block {:?}",
+                    record.key
+                );
+                continue;
+            }
+
+            inner.add(record.key, offset, next - offset);
+        }
+
+        inner.file.set_len(inner.file_len)?;
+        inner.evict(capacity);
+
+        info!(
+            "secondary cache {} loaded with {} blocks",
+            path,
+            inner.index.len()
+        );
+
+        Ok(Self {
+            path: path.to_owned(),
+            capacity,
+            inner: Mutex::new(inner),
+        })
+    }
+
+    pub fn get(&self, key: &BlockKey) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        let (offset, _) = *inner.index.get(key)?;
+
+        match read_record(&mut inner.file, offset) {
+            Ok(Some((record, _)))
+                if record.key == *key && checksum(&record.bytes) == record.checksum =>
+            {
+                Some(record.bytes)
+            }
+            _ => {
+                error!("Invalid secondary cache block {:?}, dropping it", key);
+                inner.remove(key);
+                None
+            }
+        }
+    }
+
+    /*
+     * Blocks never change, so a block which is already cached is not written again
+     */
+    pub fn insert(&self, key: BlockKey, bytes: Vec<u8>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.index.contains_key(&key) || bytes.len() > self.capacity {
+            return;
+        }
+
+        let record = Record {
+            key,
+            checksum: checksum(&bytes),
+            bytes,
+        };
+
+        match inner.append(&record) {
+            Ok((offset, length)) => inner.add(key, offset, length),
+            Err(e) => error!(
+                "Unable to write to the secondary cache {}: {}",
+                self.path, e
+            ),
+        }
+
+        inner.evict(self.capacity);
+
+        if inner.file_len > 2 * self.capacity as u64
+            && let Err(e) = self.rewrite(&mut inner)
+        {
+            error!("Unable to rewrite the secondary cache {}: {}", self.path, e);
+        }
+    }
+
+    pub fn remove_file(&self, file_id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let keys = inner
+            .index
+            .keys()
+            .filter(|key| key.file_id == file_id)
+            .copied()
+            .collect::<Vec<BlockKey>>();
+
+        keys.iter().for_each(|key| inner.remove(key));
+    }
+
+    /*
+     * Copies the live records into a new file which then replaces the old one
+     */
+    fn rewrite(&self, inner: &mut Inner) -> Result<()> {
+        info!("rewriting secondary cache {}", self.path);
+
+        let temporary_path = format!("{}.tmp", self.path);
+        let _ = fs::remove_file(&temporary_path);
+
+        let mut rewritten = Inner {
+            file: open_file(&temporary_path)?,
+            file_len: 0,
+            index: BTreeMap::new(),
+            order: VecDeque::new(),
+            usage: 0,
+        };
+
+        let live = inner
+            .order
+            .iter()
+            .filter(|(key, offset)| inner.index.get(key).is_some_and(|(o, _)| o == offset))
+            .copied()
+            .collect::<Vec<(BlockKey, u64)>>();
+
+        for (key, offset) in live {
+            let Some((record, _)) = read_record(&mut inner.file, offset)? else {
+                continue;
+            };
+
+            let (offset, length) = rewritten.append(&record)?;
+            rewritten.add(key, offset, length);
+        }
+
+        rewritten.file.sync_all()?;
+        fs::rename(&temporary_path, &self.path)?;
+
+        *inner = rewritten;
+
+        Ok(())
+    }
+}
@@ -1,149 +1,755 @@
-use log::info;
+use log::{error, info};
 use serde::{Deserialize, Serialize};
 
 use crate::config::Config;
-use crate::error::LsmError;
-use crate::structures::cache::IndexRecord;
+use crate::error::{LsmError, Result as LsmResult};
+use crate::structures::blob_manager::{BlobPointer, BlobWriter};
+use crate::structures::cache::{IndexPartition, IndexRecord, TopLevelIndex};
+use crate::structures::direct_io::{TableScanner, TableWriter};
+use crate::structures::filter_policy::{self, BLOOM, FilterPolicy, KeyFilter, LEGACY_BLOOM};
 use crate::structures::manifest::{Manifest, SSTableBasicInfo};
+use crate::structures::mmap_reader::MmapReader;
+use crate::structures::range_filter::RangeFilter;
+use crate::structures::rate_limiter::{IoPriority, RateLimiter};
+use crate::structures::table_properties::TableProperties;
 use crate::structures::{cache::Cache, memtable::MemTable};
 
-use std::fs::{File, OpenOptions};
+use std::collections::{BTreeMap, VecDeque};
+use std::io::SeekFrom;
 use std::io::prelude::*;
 use std::sync::{Arc, RwLock};
 
+pub const FORMAT_VERSION: u64 = 4;
+pub const BLOB_VALUES_VERSION: u64 = 4;
+pub const FOOTER_MAGIC: u64 = 0x4c53_4d5f_5353_5442;
+pub const FOOTER_SIZE: usize = 64;
+pub const LEGACY_FOOTER_SIZE: usize = 32;
+
+pub const PROPERTIES_BLOCK: &str = "properties";
+pub const FILTER_POLICY_BLOCK: &str = "filter_policy";
+pub const RANGE_FILTER_BLOCK: &str = "range_filter";
+
 #[derive(Debug)]
 pub struct SSTableManager {}
 
-#[derive(Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SSTableValue {
+    Inline(String),
+    Blob(BlobPointer),
+}
+
+/*
+ * Entries of one data block in key order, None is a thombstone
+ */
+pub type DataBlock = Vec<(String, Option<SSTableValue>)>;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BlockHandle {
+    pub offset: u64,
+    pub size: u64,
+}
+
+/*
+ * Version 1 files end with the first four fields only, version 2 files
+ * add the meta index and end with the magic number. Since version 3 the
+ * index points to the top level index of the partitions and the bloom
+ * filter fields are unused, every partition has its own filter. Version 4
+ * stores the filters as bitsets and values of data blocks as SSTableValue.
+ */
+#[derive(Debug, Serialize, Deserialize)]
 pub struct SSTableFooter {
     pub bloom_filter_offset: u64,
     pub bloom_filter_size: u64,
     pub index_offset: u64,
     pub index_size: u64,
+    pub meta_index_offset: u64,
+    pub meta_index_size: u64,
+    pub version: u64,
+    pub magic: u64,
 }
 
-impl SSTableManager {
-    pub fn read_from_file(
-        file_name: &str,
-        index_record: &IndexRecord,
-    ) -> Vec<(String, Option<String>)> {
-        info!(
-            "Reading from file {} offset {} and size {}",
-            file_name, index_record.offset, index_record.size
-        );
+#[derive(Deserialize)]
+struct LegacySSTableFooter {
+    bloom_filter_offset: u64,
+    bloom_filter_size: u64,
+    index_offset: u64,
+    index_size: u64,
+}
 
-        let mut file = File::open(file_name).expect("Can't open file");
+impl SSTableFooter {
+    pub fn read(file: &mut (impl Read + Seek)) -> LsmResult<Self> {
+        let mut buffer = vec![0u8; FOOTER_SIZE];
+        file.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        file.read_exact(&mut buffer)?;
 
-        let _ = file.seek(std::io::SeekFrom::Start(index_record.offset));
-        let mut buffer = vec![0u8; index_record.size as usize];
+        let footer: SSTableFooter =
+            bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))?;
 
-        let _ = file.read_exact(&mut buffer);
+        if footer.magic == FOOTER_MAGIC {
+            return Ok(footer);
+        }
 
-        bincode::deserialize(&buffer).unwrap()
+        let legacy: LegacySSTableFooter =
+            bincode::deserialize(&buffer[FOOTER_SIZE - LEGACY_FOOTER_SIZE..])
+                .map_err(|e| LsmError::SsTable(e.to_string()))?;
+
+        Ok(Self {
+            bloom_filter_offset: legacy.bloom_filter_offset,
+            bloom_filter_size: legacy.bloom_filter_size,
+            index_offset: legacy.index_offset,
+            index_size: legacy.index_size,
+            meta_index_offset: 0,
+            meta_index_size: 0,
+            version: 1,
+            magic: 0,
+        })
     }
 
-    pub fn persist(
-        mem_table: Arc<MemTable>,
-        cache: Arc<RwLock<Cache>>,
-        manifest: Arc<RwLock<Manifest>>,
+    pub fn meta_index(
+        &self,
+        file: &mut (impl Read + Seek),
+    ) -> LsmResult<BTreeMap<String, BlockHandle>> {
+        if self.version < 2 {
+            return Ok(BTreeMap::new());
+        }
+
+        let buffer = read_block(file, self.meta_index_offset, self.meta_index_size)?;
+        bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))
+    }
+}
+
+/*
+ * Data blocks written before version 4 hold the values as plain strings
+ */
+pub fn decode_data_block(buffer: &[u8], version: u64) -> LsmResult<DataBlock> {
+    if version >= BLOB_VALUES_VERSION {
+        return bincode::deserialize(buffer).map_err(|e| LsmError::SsTable(e.to_string()));
+    }
+
+    let entries: Vec<(String, Option<String>)> =
+        bincode::deserialize(buffer).map_err(|e| LsmError::SsTable(e.to_string()))?;
+
+    Ok(entries
+        .into_iter()
+        .map(|(key, value)| (key, value.map(SSTableValue::Inline)))
+        .collect())
+}
+
+pub fn read_block(file: &mut (impl Read + Seek), offset: u64, size: u64) -> LsmResult<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+
+    let mut buffer = vec![0u8; size as usize];
+    file.read_exact(&mut buffer)?;
+
+    Ok(buffer)
+}
+
+/*
+ * Iterates the entries of a file in key order, used by compaction. Only the
+ * top level index, one index partition and one data block are held in
+ * memory at a time. With direct reads the file bypasses the page cache.
+ */
+#[derive(Debug)]
+pub struct TableIterator {
+    file: TableScanner,
+    version: u64,
+    filter_policy: String,
+    partitions: VecDeque<IndexPartition>,
+    blocks: VecDeque<IndexRecord>,
+    entries: std::vec::IntoIter<(String, Option<SSTableValue>)>,
+    start: Option<String>,
+    end: Option<String>,
+}
+
+impl TableIterator {
+    pub fn open(file_name: &str, rate_limiter: Arc<RateLimiter>) -> LsmResult<Self> {
+        let mut file = TableScanner::open(file_name, Config::global().io.direct_compaction_reads)?
+            .with_rate_limiter(rate_limiter, IoPriority::Low);
+        let top_level_index = SSTableManager::read_top_level_index(&mut file)?;
+
+        Ok(Self {
+            file,
+            version: top_level_index.version,
+            filter_policy: top_level_index.filter_policy,
+            partitions: top_level_index.partitions.into(),
+            blocks: VecDeque::new(),
+            entries: Vec::new().into_iter(),
+            start: None,
+            end: None,
+        })
+    }
+
+    /*
+     * Limits the entries to [start, end), partitions and blocks outside of
+     * the range are not read at all. None is unbounded.
+     */
+    pub fn with_range(mut self, start: Option<String>, end: Option<String>) -> Self {
+        self.partitions
+            .retain(|p| start.as_ref().is_none_or(|start| p.end >= *start));
+        self.start = start;
+        self.end = end;
+        self
+    }
+
+    fn before_start(&self, key: &str) -> bool {
+        self.start
+            .as_ref()
+            .is_some_and(|start| key < start.as_str())
+    }
+
+    fn past_end(&self, key: &str) -> bool {
+        self.end.as_ref().is_some_and(|end| key >= end.as_str())
+    }
+
+    fn next_block(&mut self) -> LsmResult<Option<DataBlock>> {
+        while self.blocks.is_empty() {
+            let Some(partition) = self.partitions.pop_front() else {
+                return Ok(None);
+            };
+
+            if self.past_end(&partition.start) {
+                self.partitions.clear();
+                return Ok(None);
+            }
+
+            let (index, _) = SSTableManager::read_index_partition(
+                &mut self.file,
+                &partition,
+                &self.filter_policy,
+            )?;
+            self.blocks = index
+                .into_iter()
+                .filter(|b: &IndexRecord| !self.before_start(&b.end))
+                .collect();
+        }
+
+        let index_record = self.blocks.pop_front().expect("a block is left");
+        if self.past_end(&index_record.start) {
+            self.blocks.clear();
+            self.partitions.clear();
+            return Ok(None);
+        }
+
+        let buffer = read_block(&mut self.file, index_record.offset, index_record.size)?;
+
+        decode_data_block(&buffer, self.version).map(Some)
+    }
+}
+
+impl Iterator for TableIterator {
+    type Item = LsmResult<(String, Option<SSTableValue>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.entries.next() {
+                if self.before_start(&key) {
+                    continue;
+                }
+
+                if self.past_end(&key) {
+                    self.entries = Vec::new().into_iter();
+                    self.blocks.clear();
+                    self.partitions.clear();
+                    return None;
+                }
+
+                return Some(Ok((key, value)));
+            }
+
+            match self.next_block() {
+                Ok(Some(block)) => self.entries = block.into_iter(),
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/*
+ * Writes a table one entry at a time, entries have to be added in key order.
+ * Every index partition is written with its filter right after its data
+ * blocks, so only the keys of one partition are held in memory. The range
+ * filter is sized for the expected number of keys up front.
+ */
+#[derive(Debug)]
+pub struct TableBuilder {
+    file: TableWriter,
+    file_name: String,
+    id: usize,
+    level: usize,
+    filter_policy: Box<dyn FilterPolicy>,
+    properties: TableProperties,
+    range_filter: Option<RangeFilter>,
+    block: DataBlock,
+    partition: Vec<IndexRecord>,
+    partition_keys: Vec<String>,
+    top_level_index: Vec<IndexPartition>,
+}
+
+impl TableBuilder {
+    /*
+     * Range filters are optional, a prefix length of 0 disables them
+     */
+    pub fn create(
+        manifest: &Arc<RwLock<Manifest>>,
         level: usize,
-    ) -> Result<(), Box<dyn std::error::Error>> {
+        wal_index_range: (u64, u64),
+        expected_keys: usize,
+        (rate_limiter, priority): (Arc<RateLimiter>, IoPriority),
+    ) -> LsmResult<Self> {
         let (file_name, id) = manifest.write().unwrap().create_filename(level);
 
         info!("writing to a file {}", file_name);
 
-        let mut file = OpenOptions::new()
-            .read(true)
-            .append(true)
-            .create(true)
-            .open(&file_name)
+        let file = TableWriter::create(&file_name, Config::global().io.direct_writes)
             .ok()
-            .ok_or(LsmError::SsTable("Unable to open file".to_owned()))?;
+            .ok_or(LsmError::SsTable("Unable to open file".to_owned()))?
+            .with_rate_limiter(rate_limiter, priority);
+
+        let max_prefix_len = Config::global().ss_table.range_filter_prefix_len;
+        let range_filter = (max_prefix_len > 0).then(|| {
+            RangeFilter::new(
+                expected_keys,
+                max_prefix_len,
+                Config::global().cache.bloom_filter_false_positive_rate,
+            )
+        });
 
-        info!("updating cache with a file {} ", file_name);
+        Ok(Self {
+            file,
+            file_name,
+            id,
+            level,
+            filter_policy: filter_policy::configured()?,
+            properties: TableProperties::new(wal_index_range),
+            range_filter,
+            block: Vec::new(),
+            partition: Vec::new(),
+            partition_keys: Vec::new(),
+            top_level_index: Vec::new(),
+        })
+    }
 
-        let mut indexes: Vec<IndexRecord> = Vec::new();
-        let mut index_offset = 0;
-        let mut index_key: &str = "";
-        let mut index_end_key: &str = "";
-        let mut vect: Vec<(String, Option<String>)> = Vec::new();
+    pub fn add(&mut self, key: String, value: Option<SSTableValue>) -> LsmResult<()> {
+        self.properties.add(&key, &value);
+        if let Some(range_filter) = self.range_filter.as_mut() {
+            range_filter.add(&key);
+        }
 
-        for (index, (key, value)) in mem_table.tree.iter().enumerate() {
-            if index != 0 && index % Config::global().cache.index_size == 0 {
-                info!("Persisting vect:{:?}", vect);
+        self.partition_keys.push(key.clone());
+        self.block.push((key, value));
 
-                let bytes = bincode::serialize(&vect).unwrap();
-                let _ = file.write_all(&bytes);
+        if self.block.len() >= Config::global().cache.index_size {
+            self.write_block()?;
+        }
 
-                indexes.push(IndexRecord {
-                    start: index_key.to_owned(),
-                    end: index_end_key.to_owned(),
-                    offset: index_offset,
-                    size: bytes.len() as u64,
-                });
+        Ok(())
+    }
 
-                vect = Vec::new();
-            }
+    fn write_block(&mut self) -> LsmResult<()> {
+        info!("Persisting vect:{:?}", self.block);
 
-            if index % Config::global().cache.index_size == 0 {
-                index_offset = file.stream_position().unwrap();
-                index_key = key;
-            }
+        let offset = self.file.position();
+        let bytes = bincode::serialize(&self.block).unwrap();
+        self.file.write_all(&bytes)?;
 
-            index_end_key = key;
-            vect.push((key.clone(), value.clone()));
+        self.partition.push(IndexRecord {
+            start: self.block[0].0.clone(),
+            end: self.block[self.block.len() - 1].0.clone(),
+            offset,
+            size: bytes.len() as u64,
+        });
+        self.block.clear();
+
+        if self.partition.len() >= Config::global().ss_table.index_partition_size {
+            self.write_partition()?;
         }
 
-        info!("Persisted vect:{:?}", vect);
+        Ok(())
+    }
 
-        let bytes = bincode::serialize(&vect).unwrap();
-        let _ = file.write_all(&bytes);
+    fn write_partition(&mut self) -> LsmResult<()> {
+        info!("index partition to write {:?}", self.partition);
+
+        let keys = self
+            .partition_keys
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<&str>>();
+        let filter_bytes = self.filter_policy.build(&keys)?;
+        let filter = BlockHandle {
+            offset: self.file.position(),
+            size: filter_bytes.len() as u64,
+        };
+        self.file.write_all(&filter_bytes)?;
 
-        indexes.push(IndexRecord {
-            start: index_key.to_owned(),
-            end: index_end_key.to_owned(),
-            offset: index_offset,
-            size: bytes.len() as u64,
+        let index_bytes = bincode::serialize(&self.partition).unwrap();
+        let index = BlockHandle {
+            offset: self.file.position(),
+            size: index_bytes.len() as u64,
+        };
+        self.file.write_all(&index_bytes)?;
+
+        self.top_level_index.push(IndexPartition {
+            start: self.partition[0].start.clone(),
+            end: self.partition[self.partition.len() - 1].end.clone(),
+            index,
+            filter,
         });
+        self.partition.clear();
+        self.partition_keys.clear();
 
-        info!("indexes to write {:?}", indexes);
+        Ok(())
+    }
 
-        let bloom_filter = bincode::serialize(&mem_table.bloom_filter).unwrap();
-        let bloom_filter_offset = file.stream_position().unwrap();
-        let bloom_filter_size = bloom_filter.len() as u64;
+    /*
+     * Writes what is left of the last partition and the meta blocks, syncs
+     * the file and caches its index. The table is left out of the manifest.
+     */
+    pub fn finish(mut self, cache: &Arc<RwLock<Cache>>) -> LsmResult<SSTableBasicInfo> {
+        if !self.block.is_empty() {
+            self.write_block()?;
+        }
+        if !self.partition.is_empty() {
+            self.write_partition()?;
+        }
+
+        info!("top level index to write {:?}", self.top_level_index);
 
-        let _ = file.write_all(&bloom_filter);
+        let index_offset = self.file.position();
 
-        let index_offset = file.stream_position().unwrap();
+        let index_bytes = bincode::serialize(&self.top_level_index).unwrap();
+        self.file.write_all(&index_bytes)?;
 
-        let index_bytes = bincode::serialize(&indexes).unwrap();
-        let _ = file.write_all(&index_bytes);
+        info!("table properties to write {:?}", self.properties);
+
+        let properties_bytes = bincode::serialize(&self.properties).unwrap();
+        let mut meta_index = BTreeMap::new();
+        meta_index.insert(
+            PROPERTIES_BLOCK.to_owned(),
+            BlockHandle {
+                offset: self.file.position(),
+                size: properties_bytes.len() as u64,
+            },
+        );
+        self.file.write_all(&properties_bytes)?;
+
+        let filter_policy_bytes = self.filter_policy.name().as_bytes();
+        meta_index.insert(
+            FILTER_POLICY_BLOCK.to_owned(),
+            BlockHandle {
+                offset: self.file.position(),
+                size: filter_policy_bytes.len() as u64,
+            },
+        );
+        self.file.write_all(filter_policy_bytes)?;
+
+        if let Some(range_filter) = self.range_filter.as_ref() {
+            let range_filter_bytes = bincode::serialize(range_filter).unwrap();
+            meta_index.insert(
+                RANGE_FILTER_BLOCK.to_owned(),
+                BlockHandle {
+                    offset: self.file.position(),
+                    size: range_filter_bytes.len() as u64,
+                },
+            );
+            self.file.write_all(&range_filter_bytes)?;
+        }
+
+        let meta_index_offset = self.file.position();
+        let meta_index_bytes = bincode::serialize(&meta_index).unwrap();
+        self.file.write_all(&meta_index_bytes)?;
 
         let footer = SSTableFooter {
-            bloom_filter_offset,
-            bloom_filter_size,
+            bloom_filter_offset: 0,
+            bloom_filter_size: 0,
             index_offset,
             index_size: index_bytes.len() as u64,
+            meta_index_offset,
+            meta_index_size: meta_index_bytes.len() as u64,
+            version: FORMAT_VERSION,
+            magic: FOOTER_MAGIC,
         };
 
         let footer_bytes = bincode::serialize(&footer).unwrap();
-        let _ = file.write_all(&footer_bytes);
+        self.file.write_all(&footer_bytes)?;
+        let file_size = self.file.position();
+        self.file.finish()?;
+
+        info!("updating cache with a file {} ", self.file_name);
+
+        cache.write().unwrap().add(
+            self.id,
+            &self.file_name,
+            TopLevelIndex {
+                version: FORMAT_VERSION,
+                filter_policy: self.filter_policy.name().to_owned(),
+                range_filter: self.range_filter,
+                partitions: self.top_level_index,
+            },
+        );
 
-        cache
-            .write()
-            .unwrap()
-            .add(&file_name, mem_table.bloom_filter.clone(), indexes);
+        Ok(SSTableBasicInfo::new(
+            self.id,
+            self.file_name,
+            self.level,
+            self.properties.min_key,
+            self.properties.max_key,
+            file_size,
+            self.properties.creation_time,
+        ))
+    }
+
+    /*
+     * Deletes the file of a table which is not going to be finished
+     */
+    pub fn abandon(self) {
+        info!("abandoning the table {}", self.file_name);
+
+        drop(self.file);
+        let _ = std::fs::remove_file(&self.file_name);
+    }
+}
+
+impl SSTableManager {
+    pub fn read_from_file(
+        cache: &Cache,
+        file_name: &str,
+        index_record: &IndexRecord,
+        version: u64,
+    ) -> LsmResult<DataBlock> {
+        info!(
+            "Reading from file {} offset {} and size {}",
+            file_name, index_record.offset, index_record.size
+        );
+
+        if Config::global().ss_table.mmap_reads {
+            let block = cache.mmap_reader().map(file_name).and_then(|mmap| {
+                let bytes = MmapReader::block(&mmap, index_record.offset, index_record.size)?;
+                decode_data_block(bytes, version)
+            });
+
+            match block {
+                Ok(block) => return Ok(block),
+                Err(e) => error!("Unable to read mapped block from {}: {}", file_name, e),
+            }
+        }
+
+        let buffer = cache
+            .table_cache()
+            .open(file_name)?
+            .read_block(index_record.offset, index_record.size)?;
+
+        decode_data_block(&buffer, version)
+    }
+
+    /*
+     * Files written before version 3 have a single index and bloom filter,
+     * they are treated as one partition spanning the whole file
+     */
+    pub fn read_top_level_index(file: &mut (impl Read + Seek)) -> LsmResult<TopLevelIndex> {
+        let footer = SSTableFooter::read(file)?;
+        let mut meta_index = footer.meta_index(file)?;
+        let filter_policy = SSTableManager::read_filter_policy(
+            file,
+            &footer,
+            meta_index.remove(FILTER_POLICY_BLOCK),
+        )?;
+        let range_filter = meta_index
+            .remove(RANGE_FILTER_BLOCK)
+            .map(|handle| {
+                let buffer = read_block(file, handle.offset, handle.size)?;
+                bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))
+            })
+            .transpose()?;
+        let buffer = read_block(file, footer.index_offset, footer.index_size)?;
+
+        if footer.version >= 3 {
+            return Ok(TopLevelIndex {
+                version: footer.version,
+                filter_policy,
+                range_filter,
+                partitions: bincode::deserialize(&buffer)
+                    .map_err(|e| LsmError::SsTable(e.to_string()))?,
+            });
+        }
+
+        let indexes: Vec<IndexRecord> =
+            bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))?;
+
+        let (Some(first), Some(last)) = (indexes.first(), indexes.last()) else {
+            return Ok(TopLevelIndex {
+                version: footer.version,
+                filter_policy,
+                range_filter,
+                partitions: Vec::new(),
+            });
+        };
+
+        let partition = IndexPartition {
+            start: first.start.clone(),
+            end: last.end.clone(),
+            index: BlockHandle {
+                offset: footer.index_offset,
+                size: footer.index_size,
+            },
+            filter: BlockHandle {
+                offset: footer.bloom_filter_offset,
+                size: footer.bloom_filter_size,
+            },
+        };
+
+        Ok(TopLevelIndex {
+            version: footer.version,
+            filter_policy,
+            range_filter,
+            partitions: vec![partition],
+        })
+    }
+
+    /*
+     * Files without a filter policy block use bloom filters, in the legacy
+     * layout before version 4
+     */
+    fn read_filter_policy(
+        file: &mut (impl Read + Seek),
+        footer: &SSTableFooter,
+        handle: Option<BlockHandle>,
+    ) -> LsmResult<String> {
+        let Some(handle) = handle else {
+            let filter_policy = if footer.version < 4 {
+                LEGACY_BLOOM
+            } else {
+                BLOOM
+            };
+            return Ok(filter_policy.to_owned());
+        };
+
+        let buffer = read_block(file, handle.offset, handle.size)?;
+        String::from_utf8(buffer).map_err(|e| LsmError::SsTable(e.to_string()))
+    }
+
+    pub fn read_index_partition(
+        file: &mut (impl Read + Seek),
+        partition: &IndexPartition,
+        filter_policy: &str,
+    ) -> LsmResult<(Vec<IndexRecord>, Box<dyn KeyFilter>)> {
+        let buffer = read_block(file, partition.index.offset, partition.index.size)?;
+        let index = bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))?;
+
+        let buffer = read_block(file, partition.filter.offset, partition.filter.size)?;
+        let filter = filter_policy::from_name(filter_policy)?.decode(&buffer)?;
+
+        Ok((index, filter))
+    }
+
+    /*
+     * Returns None for files written before the properties block existed
+     */
+    pub fn read_properties(cache: &Cache, file_name: &str) -> LsmResult<Option<TableProperties>> {
+        Ok(cache.table_cache().open(file_name)?.properties.clone())
+    }
+
+    pub fn persist(
+        mem_table: Arc<MemTable>,
+        cache: Arc<RwLock<Cache>>,
+        manifest: Arc<RwLock<Manifest>>,
+        level: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut blob_writer = BlobWriter::new(Arc::clone(&manifest));
+
+        let entries = mem_table
+            .tree
+            .iter()
+            .map(|(key, value)| {
+                let value = value
+                    .as_ref()
+                    .map(|value| blob_writer.separate(value))
+                    .transpose()?;
+
+                Ok((key.clone(), value))
+            })
+            .collect::<LsmResult<Vec<(String, Option<SSTableValue>)>>>()?;
+
+        blob_writer.finish()?;
+
+        let wal_index = mem_table.wal_index as u64;
+
+        SSTableManager::write_table(
+            entries,
+            (wal_index, wal_index),
+            cache,
+            manifest,
+            level,
+            IoPriority::High,
+        )?;
+
+        Ok(())
+    }
+
+    pub fn write_table(
+        entries: Vec<(String, Option<SSTableValue>)>,
+        wal_index_range: (u64, u64),
+        cache: Arc<RwLock<Cache>>,
+        manifest: Arc<RwLock<Manifest>>,
+        level: usize,
+        priority: IoPriority,
+    ) -> Result<SSTableBasicInfo, Box<dyn std::error::Error>> {
+        let ss_table = SSTableManager::write_file(
+            entries,
+            wal_index_range,
+            cache,
+            Arc::clone(&manifest),
+            level,
+            priority,
+        )?;
 
         manifest
             .write()
             .expect("unable to open manifest for writes")
-            .add(SSTableBasicInfo::new(
-                id,
-                file_name.to_string(),
-                level,
-                "".to_string(),
-                "".to_string(),
-            ));
-        Ok(())
+            .add(ss_table.clone());
+
+        Ok(ss_table)
+    }
+
+    /*
+     * Writes the file and caches its index, the table is left out of the
+     * manifest until the caller adds it
+     */
+    pub fn write_file(
+        entries: Vec<(String, Option<SSTableValue>)>,
+        wal_index_range: (u64, u64),
+        cache: Arc<RwLock<Cache>>,
+        manifest: Arc<RwLock<Manifest>>,
+        level: usize,
+        priority: IoPriority,
+    ) -> Result<SSTableBasicInfo, Box<dyn std::error::Error>> {
+        let rate_limiter = cache.read().unwrap().rate_limiter();
+        let mut builder = TableBuilder::create(
+            &manifest,
+            level,
+            wal_index_range,
+            entries.len(),
+            (rate_limiter, priority),
+        )?;
+
+        for (key, value) in entries {
+            builder.add(key, value)?;
+        }
+
+        Ok(builder.finish(&cache)?)
+    }
+
+    /*
+     * Estimated bytes of an entry inside of a data block
+     */
+    pub fn entry_size(key: &str, value: &Option<SSTableValue>) -> u64 {
+        let value_size = match value {
+            Some(SSTableValue::Inline(value)) => value.len(),
+            Some(SSTableValue::Blob(_)) => std::mem::size_of::<BlobPointer>(),
+            None => 0,
+        };
+
+        (key.len() + value_size) as u64
     }
 }
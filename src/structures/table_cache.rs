@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::{info, trace};
+
+use crate::config::Config;
+use crate::error::{LsmError, Result};
+use crate::structures::ss_table_manager::{PROPERTIES_BLOCK, SSTableFooter, read_block};
+use crate::structures::table_properties::TableProperties;
+
+/*
+ * An open ss_table with its footer and properties parsed once on open
+ */
+#[derive(Debug)]
+pub struct TableReader {
+    pub path: String,
+    pub footer: SSTableFooter,
+    pub properties: Option<TableProperties>,
+    file: Mutex<File>,
+}
+
+/*
+ * Open ss_tables keyed by their id, at most max_open_files of them are kept
+ * open and the least recently used one is closed first
+ */
+#[derive(Debug, Default)]
+pub struct TableCache {
+    tables: Mutex<OpenTables>,
+}
+
+#[derive(Debug, Default)]
+struct OpenTables {
+    entries: BTreeMap<usize, (Arc<TableReader>, u64)>,
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+}
+
+/*
+ * ss_tables are named L{level}_{id}.sst
+ */
+pub fn table_id(file_name: &str) -> Option<usize> {
+    let stem = Path::new(file_name).file_stem()?.to_str()?;
+    let (_, id) = stem.split_once('_')?;
+
+    id.parse().ok()
+}
+
+impl TableReader {
+    pub fn open(file_name: &str) -> Result<Self> {
+        info!("opening table {}", file_name);
+
+        let mut file = File::open(file_name)?;
+        let footer = SSTableFooter::read(&mut file)?;
+
+        let properties = match footer.meta_index(&mut file)?.remove(PROPERTIES_BLOCK) {
+            Some(handle) => {
+                let buffer = read_block(&mut file, handle.offset, handle.size)?;
+                Some(bincode::deserialize(&buffer).map_err(|e| LsmError::SsTable(e.to_string()))?)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            path: file_name.to_owned(),
+            footer,
+            properties,
+            file: Mutex::new(file),
+        })
+    }
+
+    /*
+     * The handle is shared, so it stays locked from the seek until the read is done
+     */
+    pub fn file(&self) -> MutexGuard<'_, File> {
+        self.file.lock().unwrap()
+    }
+
+    pub fn read_block(&self, offset: u64, size: u64) -> Result<Vec<u8>> {
+        read_block(&mut *self.file(), offset, size)
+    }
+}
+
+impl TableCache {
+    /*
+     * Files whose name holds no id are opened without being cached
+     */
+    pub fn open(&self, file_name: &str) -> Result<Arc<TableReader>> {
+        let Some(id) = table_id(file_name) else {
+            return Ok(Arc::new(TableReader::open(file_name)?));
+        };
+
+        if let Some(reader) = self.tables.lock().unwrap().get(id, file_name) {
+            return Ok(reader);
+        }
+
+        let reader = Arc::new(TableReader::open(file_name)?);
+        self.tables.lock().unwrap().insert(
+            id,
+            Arc::clone(&reader),
+            Config::global().ss_table.max_open_files,
+        );
+
+        Ok(reader)
+    }
+
+    pub fn evict(&self, file_name: &str) {
+        if let Some(id) = table_id(file_name) {
+            self.tables.lock().unwrap().remove(id);
+        }
+    }
+}
+
+impl OpenTables {
+    fn get(&mut self, id: usize, file_name: &str) -> Option<Arc<TableReader>> {
+        self.tick += 1;
+        let tick = self.tick;
+
+        let (reader, last_used) = self.entries.get_mut(&id)?;
+        if reader.path != file_name {
+            return None;
+        }
+
+        self.lru.remove(last_used);
+        self.lru.insert(tick, id);
+        *last_used = tick;
+
+        Some(Arc::clone(reader))
+    }
+
+    fn insert(&mut self, id: usize, reader: Arc<TableReader>, capacity: usize) {
+        self.remove(id);
+
+        self.tick += 1;
+        self.entries.insert(id, (reader, self.tick));
+        self.lru.insert(self.tick, id);
+
+        while self.entries.len() > capacity.max(1) {
+            let Some((_, oldest)) = self.lru.pop_first() else {
+                break;
+            };
+
+            trace!("closing table {}", oldest);
+            self.entries.remove(&oldest);
+        }
+    }
+
+    fn remove(&mut self, id: usize) {
+        if let Some((_, last_used)) = self.entries.remove(&id) {
+            self.lru.remove(&last_used);
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::structures::ss_table_manager::SSTableValue;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum CompressionType {
+    #[default]
+    None,
+}
+
+/*
+ * Summary of an ss_table written into its properties meta block.
+ * There are no per entry sequence numbers, the table records the range
+ * of WAL segment indexes its entries were written to instead.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableProperties {
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    pub raw_key_size: u64,
+    pub raw_value_size: u64,
+    pub encoded_key_size: u64,
+    pub encoded_value_size: u64,
+    pub min_key: String,
+    pub max_key: String,
+    pub smallest_wal_index: u64,
+    pub largest_wal_index: u64,
+    pub creation_time: u64,
+    pub compression_type: CompressionType,
+}
+
+impl TableProperties {
+    pub fn new((smallest_wal_index, largest_wal_index): (u64, u64)) -> Self {
+        let creation_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+
+        Self {
+            smallest_wal_index,
+            largest_wal_index,
+            creation_time,
+            ..Default::default()
+        }
+    }
+
+    /*
+     * Entries have to be added in key order
+     */
+    pub fn add(&mut self, key: &str, value: &Option<SSTableValue>) {
+        if self.entry_count == 0 {
+            self.min_key = key.to_owned();
+        }
+        self.max_key = key.to_owned();
+
+        self.entry_count += 1;
+        self.raw_key_size += key.len() as u64;
+        self.encoded_key_size += bincode::serialized_size(key).unwrap_or_default();
+        self.encoded_value_size += bincode::serialized_size(value).unwrap_or_default();
+
+        match value {
+            None => self.tombstone_count += 1,
+            Some(SSTableValue::Inline(value)) => self.raw_value_size += value.len() as u64,
+            Some(SSTableValue::Blob(pointer)) => self.raw_value_size += pointer.size,
+        }
+    }
+}
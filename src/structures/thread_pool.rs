@@ -0,0 +1,66 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use log::{error, trace};
+
+type Task = Box<dyn FnOnce() + Send>;
+
+/*
+ * Fixed number of threads running the tasks in the order they were queued.
+ * A panicking task is logged and the thread moves on to the next one.
+ * Dropping the pool lets the threads finish their current task and exit.
+ */
+#[derive(Debug)]
+pub struct ThreadPool {
+    sender: Sender<Task>,
+    size: usize,
+}
+
+fn work(name: &str, receiver: &Mutex<Receiver<Task>>) {
+    loop {
+        let task = receiver.lock().unwrap().recv();
+        let Ok(task) = task else {
+            trace!("{} thread exiting", name);
+            return;
+        };
+
+        if panic::catch_unwind(AssertUnwindSafe(task)).is_err() {
+            error!("A {} task panicked", name);
+        }
+    }
+}
+
+impl ThreadPool {
+    pub fn new(name: &str, size: usize) -> Self {
+        let size = size.max(1);
+        let (sender, receiver) = mpsc::channel::<Task>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for index in 0..size {
+            let receiver = Arc::clone(&receiver);
+            let thread_name = format!("{}-{}", name, index);
+
+            let spawned = thread::Builder::new()
+                .name(thread_name.clone())
+                .spawn(move || work(&thread_name, &receiver));
+
+            if let Err(e) = spawned {
+                error!("Unable to start a {} thread: {}", name, e);
+            }
+        }
+
+        Self { sender, size }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn execute(&self, task: impl FnOnce() + Send + 'static) {
+        if self.sender.send(Box::new(task)).is_err() {
+            error!("No thread left to run the task");
+        }
+    }
+}
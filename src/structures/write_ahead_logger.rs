@@ -100,7 +100,10 @@ impl WriteAheadLogger {
             return MemTable::default();
         };
 
-        let mut tree = MemTable::default();
+        let mut tree = MemTable {
+            wal_index: index(),
+            ..Default::default()
+        };
 
         data.split("|").take_while(|v| !v.is_empty()).for_each(|v| {
             let wal_record: WALRecord = bincode::deserialize(v.as_bytes()).unwrap();
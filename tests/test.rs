@@ -1,12 +1,46 @@
-use std::fs;
+use std::{
+    collections::BTreeSet,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc, RwLock,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use log::info;
-use lsm_tree::{config::Config, error::LsmError, structures::lsm::Lsm};
+use lsm_tree::{
+    config::Config,
+    error::LsmError,
+    structures::{
+        block_cache::{Block, BlockCache, BlockKey, Priority},
+        bloom_filter::BloomFilter,
+        cache::{Cache, IndexRecord},
+        compaction_filter::{CompactionFilter, FilterDecision},
+        compaction_strategy::{
+            self, CompactionJob, CompactionStrategy, FIFO, FifoCompaction, LEVELED, SIZE_TIERED,
+        },
+        filter_policy,
+        lsm::Lsm,
+        manifest::{Manifest, SSTableBasicInfo},
+        merge_iterator::MergeIterator,
+        range_filter::RangeFilter,
+        rate_limiter::{IoPriority, RateLimiter},
+        row_cache::RowCache,
+        secondary_cache::SecondaryCache,
+        ss_table_manager::{SSTableManager, SSTableValue},
+        table_cache,
+        thread_pool::ThreadPool,
+    },
+};
 
 #[tokio::test]
 #[serial_test::serial]
 pub async fn test_recreating() -> Result<(), Box<dyn std::error::Error>> {
-    let config = Config::test();
+    let config = set_up();
 
     let _ = log4rs::init_file(&config.directory.log, Default::default())
         .map_err(|e| LsmError::Log(e.to_string()));
@@ -32,26 +66,1748 @@ pub async fn test_recreating() -> Result<(), Box<dyn std::error::Error>> {
     let lsm = Lsm::default();
     info!("lsm after inserting the values {:?}", lsm);
 
-    let val = lsm.get("1");
+    let val = lsm.get("1")?;
     assert_eq!(val, Some("test1".to_owned()));
 
-    let val = lsm.get("4");
+    let val = lsm.get("4")?;
     assert_eq!(val, Some("test4".to_owned()));
 
-    let val = lsm.get("6");
+    let val = lsm.get("6")?;
     assert_eq!(val, Some("test6".to_owned()));
 
-    let val = lsm.get("2");
+    let val = lsm.get("2")?;
     assert_eq!(val, None);
 
-    let val = lsm.get("abc");
+    let val = lsm.get("abc")?;
     assert_eq!(val, None);
 
     tear_down(config);
 
     Ok(())
 }
-fn tear_down(config: &Config) {
-    let _ = fs::remove_dir_all(&config.directory.wal);
-    let _ = fs::remove_dir_all(&config.directory.ss_table);
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_interrupted_manifest_write() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..11 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    // a crash while writing the manifest leaves a partial temporary file behind
+    let temporary_path = format!("{}.tmp", config.ss_table.manifest_location);
+    assert!(!fs::exists(&temporary_path)?);
+    fs::write(&temporary_path, "{\"version\": 1, \"ss_ta")?;
+
+    let lsm = Lsm::default();
+    assert_eq!(Manifest::read_from_file()?.ss_tables().len(), 1);
+    assert_eq!(lsm.get("03")?, Some("value".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_baseline_table() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    // baseline tables hold plain string values, one usize per bloom filter bit, a single index and a footer of four fields
+    let entries = vec![
+        ("a".to_owned(), Some("value a".to_owned())),
+        ("b".to_owned(), None),
+        ("c".to_owned(), Some("value c".to_owned())),
+    ];
+    let data = bincode::serialize(&entries)?;
+
+    let size = 64;
+    let mut bloom_filter = vec![0usize; size];
+    for (key, _) in entries.iter() {
+        for seed in 1..=3 {
+            bloom_filter
+                [xxhash_rust::xxh3::xxh3_64_with_seed(key.as_bytes(), seed) as usize % size] = 1;
+        }
+    }
+    let bloom_filter = bincode::serialize(&(bloom_filter, size))?;
+
+    let index = bincode::serialize(&vec![IndexRecord {
+        start: "a".to_owned(),
+        end: "c".to_owned(),
+        offset: 0,
+        size: data.len() as u64,
+    }])?;
+    let footer = bincode::serialize(&(
+        data.len() as u64,
+        bloom_filter.len() as u64,
+        (data.len() + bloom_filter.len()) as u64,
+        index.len() as u64,
+    ))?;
+
+    let path = format!("{}/L0_{:010}.sst", config.directory.ss_table, 1);
+    fs::write(&path, [data, bloom_filter, index, footer].concat())?;
+    fs::write(
+        &config.ss_table.manifest_location,
+        serde_json::json!({
+            "version": 1,
+            "next_id": 2,
+            "ss_tables": [{ "id": 1, "path": path, "level": 0, "min_key": "", "max_key": "" }],
+        })
+        .to_string(),
+    )?;
+
+    let mut lsm = Lsm::default();
+
+    assert_eq!(lsm.get("a")?, Some("value a".to_owned()));
+    assert_eq!(lsm.get("b")?, None);
+    assert_eq!(lsm.get("c")?, Some("value c".to_owned()));
+    assert_eq!(
+        lsm.scan("a", "z")?,
+        vec![
+            ("a".to_owned(), "value a".to_owned()),
+            ("c".to_owned(), "value c".to_owned()),
+        ]
+    );
+
+    // compaction reads the table block by block and rewrites it in the current format
+    lsm.compact_range("a", "z", 1)?;
+    lsm.wait_for_background_work().await?;
+    assert!(!fs::exists(&path)?);
+
+    let lsm = Lsm::default();
+    assert_eq!(lsm.get("a")?, Some("value a".to_owned()));
+    assert_eq!(lsm.get("b")?, None);
+    assert_eq!(lsm.get("c")?, Some("value c".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_blob_separation() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    let large_value = |i: usize| format!("{}{}", "x".repeat(config.blob.value_size_threshold), i);
+
+    for i in 0..21 {
+        let _ = lsm.add(&format!("{:02}", i), &large_value(i));
+    }
+    let _ = lsm.add("small", "value");
+
+    lsm.wait_for_background_work().await?;
+
+    let blob_files = fs::read_dir(&config.directory.blob)?.count();
+    assert!(blob_files > 0);
+
+    let lsm = Lsm::default();
+
+    let val = lsm.get("01")?;
+    assert_eq!(val, Some(large_value(1)));
+
+    let val = lsm.get("15")?;
+    assert_eq!(val, Some(large_value(15)));
+
+    let val = lsm.get("small")?;
+    assert_eq!(val, Some("value".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_blob_garbage_collection() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    let large_value = |version: usize, i: usize| {
+        format!(
+            "{}{}",
+            version.to_string().repeat(config.blob.value_size_threshold),
+            i
+        )
+    };
+
+    // the first flush writes all ten values into one blob file
+    for i in 0..10 {
+        let _ = lsm.add(&i.to_string(), &large_value(1, i));
+    }
+
+    // the second flush overwrites eight of them and deletes one more
+    for i in 0..8 {
+        let _ = lsm.add(&i.to_string(), &large_value(2, i));
+    }
+    let _ = lsm.delete("8");
+    let _ = lsm.add("small", "value");
+    let _ = lsm.add("trigger", "flush");
+
+    lsm.wait_for_background_work().await?;
+
+    let mut blob_files = fs::read_dir(&config.directory.blob)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    blob_files.sort();
+    assert_eq!(blob_files.len(), 2);
+
+    // nine of the ten values of the first blob file become garbage
+    lsm.compact_range("0", "z", 1)?;
+    assert!(blob_files[0].exists());
+
+    // the next compaction relocates the live value, nothing points into the file anymore
+    lsm.compact_range("0", "z", 2)?;
+    assert!(!blob_files[0].exists());
+    assert!(blob_files[1].exists());
+
+    let lsm = Lsm::default();
+
+    for i in 0..8 {
+        assert_eq!(lsm.get(&i.to_string())?, Some(large_value(2, i)));
+    }
+    assert_eq!(lsm.get("8")?, None);
+    assert_eq!(lsm.get("9")?, Some(large_value(1, 9)));
+    assert_eq!(lsm.get("small")?, Some("value".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_table_properties() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..9 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+    let _ = lsm.delete("05");
+    let _ = lsm.delete("99");
+    let _ = lsm.add("10", "value");
+
+    lsm.wait_for_background_work().await?;
+
+    let tables = lsm.table_properties()?;
+    assert_eq!(tables.len(), 1);
+
+    let (table, properties) = &tables[0];
+    assert_eq!(table.min_key, "00");
+    assert_eq!(table.max_key, "99");
+    assert_eq!(properties.entry_count, 10);
+    assert_eq!(properties.tombstone_count, 2);
+    assert_eq!(properties.raw_key_size, 20);
+    assert_eq!(properties.raw_value_size, 40);
+    assert_eq!(properties.min_key, "00");
+    assert_eq!(properties.max_key, "99");
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_scan() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..11 {
+        let _ = lsm.add(&format!("a{:02}", i), "old");
+    }
+    let _ = lsm.delete("a03");
+    let _ = lsm.add("a04", "new");
+    let _ = lsm.add("b00", "new");
+
+    lsm.wait_for_background_work().await?;
+
+    let keys = |result: Vec<(String, String)>| {
+        result
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect::<Vec<String>>()
+    };
+
+    assert_eq!(keys(lsm.scan("a02", "a06")?), vec!["a02", "a04", "a05"]);
+    assert_eq!(
+        lsm.scan("a04", "a05")?,
+        vec![("a04".to_owned(), "new".to_owned())]
+    );
+    assert_eq!(keys(lsm.scan("a09", "c")?), vec!["a09", "a10", "b00"]);
+    assert!(lsm.scan("x", "z")?.is_empty());
+    assert!(lsm.scan("a05", "a02")?.is_empty());
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_block_cache_hits() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    // the second flush moves the first batch out of the immutable memtable
+    for i in 0..21 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    // a different key of the same data block, the row cache would serve the same key again
+    assert_eq!(lsm.get("03")?, Some("value".to_owned()));
+    let before = lsm.block_cache_stats();
+    assert_eq!(lsm.get("04")?, Some("value".to_owned()));
+    let after = lsm.block_cache_stats();
+
+    assert!(after.hits > before.hits);
+    assert_eq!(after.misses, before.misses);
+    assert!(after.usage > 0 && after.usage <= after.capacity);
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_more_tables_than_open_files() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    // four flushed tables, while the test config keeps two of them open
+    for i in 0..41 {
+        let _ = lsm.add(&format!("{:02}", i), &format!("value{}", i));
+        if i % 10 == 0 {
+            lsm.wait_for_background_work().await?;
+        }
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    let lsm = Lsm::default();
+
+    for i in (0..40).rev().chain(0..40) {
+        assert_eq!(lsm.get(&format!("{:02}", i))?, Some(format!("value{}", i)));
+    }
+    assert_eq!(lsm.table_properties()?.len(), 4);
+    assert_eq!(
+        table_cache::table_id("test_data/ss_tables/L1_0000000012.sst"),
+        Some(12)
+    );
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_lazy_metadata_loading() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    // the second flush keeps the first batch out of the replayed WAL
+    for i in 0..21 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    // files the manifest does not know about are never read
+    fs::write(
+        format!("{}/stray.sst", config.directory.ss_table),
+        "not an ss_table",
+    )?;
+    let lsm = Lsm::default();
+    assert_eq!(lsm.get("03")?, Some("value".to_owned()));
+
+    // a listed file which is gone is an error, not a panic
+    let (ss_table, _) = lsm
+        .table_properties()?
+        .into_iter()
+        .find(|(_, properties)| properties.min_key == "00")
+        .unwrap();
+    fs::remove_file(&ss_table.path)?;
+
+    let lsm = Lsm::default();
+    assert!(lsm.get("05").is_err());
+    assert!(lsm.scan("00", "99").is_err());
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_row_cache_invalidation() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..21 {
+        let _ = lsm.add(&format!("{:02}", i), "old");
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    assert_eq!(lsm.get("03")?, Some("old".to_owned()));
+    assert_eq!(lsm.get("04")?, Some("old".to_owned()));
+    assert_eq!(lsm.get("99")?, None);
+
+    let _ = lsm.add("03", "new");
+    let _ = lsm.delete("04");
+    let _ = lsm.add("99", "new");
+
+    // two more flushes move the changes out of both memtables
+    for i in 21..41 {
+        let _ = lsm.add(&format!("{:02}", i), "old");
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    assert_eq!(lsm.get("03")?, Some("new".to_owned()));
+    assert_eq!(lsm.get("04")?, None);
+    assert_eq!(lsm.get("99")?, Some("new".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_pending_flushes_stay_readable() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    let _ = lsm.add("k", "old");
+    for i in 0..config.memtable.max_entries {
+        let _ = lsm.add(&format!("{:02}", i), "filler");
+    }
+
+    lsm.wait_for_background_work().await?;
+    assert_eq!(lsm.get("k")?, Some("old".to_owned()));
+
+    // two flushes start before either runs, the first one holds the newer value
+    let _ = lsm.add("k", "new");
+    for i in 0..2 * config.memtable.max_entries {
+        let _ = lsm.add(&format!("{:02}", i), "filler");
+    }
+
+    assert_eq!(lsm.get("k")?, Some("new".to_owned()));
+    assert_eq!(
+        lsm.scan("k", "l")?,
+        vec![("k".to_owned(), "new".to_owned())]
+    );
+
+    lsm.wait_for_background_work().await?;
+    assert_eq!(lsm.get("k")?, Some("new".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_row_cache_skips_failed_reads() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    let large_value = |i: usize| format!("{}{}", "x".repeat(config.blob.value_size_threshold), i);
+
+    for i in 0..21 {
+        let _ = lsm.add(&format!("{:02}", i), &large_value(i));
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    // a blob which can't be read is an error, which must not be cached as a thombstone
+    let moved = format!("{}_moved", config.directory.blob);
+    fs::rename(&config.directory.blob, &moved)?;
+    assert!(lsm.get("03").is_err());
+
+    fs::rename(&moved, &config.directory.blob)?;
+    assert_eq!(lsm.get("03")?, Some(large_value(3)));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_row_cache_eviction() {
+    let cache = RowCache::new(10);
+
+    cache.insert("a", Some("1234".to_owned()));
+    cache.insert("b", None);
+    assert_eq!(cache.get("a"), Some(Some("1234".to_owned())));
+
+    // the least recently used row makes room
+    cache.insert("c", Some("1234".to_owned()));
+    assert_eq!(cache.get("b"), None);
+    assert!(cache.get("a").is_some() && cache.get("c").is_some());
+
+    cache.insert("d", Some("too large for the cache".to_owned()));
+    assert_eq!(cache.get("d"), None);
+
+    cache.invalidate("a");
+    assert_eq!(cache.get("a"), None);
+}
+
+#[test]
+pub fn test_block_cache_eviction() {
+    let cache = BlockCache::new(100, 1);
+    let block = || Block::Data(Arc::new(Vec::new()));
+    let key = |offset| BlockKey { file_id: 1, offset };
+
+    cache.insert(key(0), block(), 40, Priority::High);
+    cache.insert(key(1), block(), 40, Priority::Low);
+    cache.insert(key(2), block(), 40, Priority::Low);
+
+    // the older low priority block goes first, even though the pinned one is older
+    assert!(cache.get(&key(0)).is_some());
+    assert!(cache.get(&key(1)).is_none());
+    assert!(cache.get(&key(2)).is_some());
+
+    cache.insert(key(3), block(), 200, Priority::Low);
+    assert!(cache.get(&key(3)).is_none());
+
+    cache.remove_file(1);
+    let stats = cache.stats();
+    assert_eq!(stats.usage, 0);
+    assert_eq!((stats.hits, stats.misses), (2, 2));
+
+    // pinned blocks beyond the high priority pool are evicted like data blocks
+    let cache = BlockCache::new(100, 1).with_high_priority_pool_ratio(0.5);
+    cache.insert(key(0), block(), 40, Priority::High);
+    cache.insert(key(1), block(), 40, Priority::High);
+    cache.insert(key(2), block(), 20, Priority::Low);
+    cache.insert(key(3), block(), 20, Priority::Low);
+
+    assert!(cache.get(&key(0)).is_none());
+    assert!(cache.get(&key(1)).is_some());
+    assert!(cache.get(&key(2)).is_some());
+    assert!(cache.get(&key(3)).is_some());
+
+    // a shard count leaving shards too small for a block is reduced
+    let cache = BlockCache::new(100, 16);
+    cache.insert(key(0), block(), 60, Priority::Low);
+    assert!(cache.get(&key(0)).is_some());
+}
+
+#[test]
+#[serial_test::serial]
+pub fn test_ids_survive_restart() {
+    let config = set_up();
+
+    // blocks are cached under the id of their file, so a restart must not hand out an id again
+    let (_, id) = Manifest::new().create_filename(0);
+    let (_, blob_id) = Manifest::new().create_blob_filename();
+    let (_, next_id) = Manifest::new().create_filename(0);
+    assert!(id < blob_id && blob_id < next_id);
+
+    tear_down(config);
+}
+
+#[test]
+pub fn test_secondary_cache() -> Result<(), Box<dyn std::error::Error>> {
+    let path = "test_data/secondary_cache_test/blocks.cache";
+    let _ = fs::remove_file(path);
+    let key = |offset| BlockKey { file_id: 1, offset };
+    let entries = vec![("key".to_owned(), None)];
+
+    // blocks evicted from memory spill to the secondary cache and come back from there
+    let cache = BlockCache::new(100, 1).with_secondary(SecondaryCache::open(path, 1000)?);
+    cache.insert(
+        key(0),
+        Block::Data(Arc::new(entries.clone())),
+        60,
+        Priority::Low,
+    );
+    cache.insert(
+        key(1),
+        Block::Data(Arc::new(entries.clone())),
+        60,
+        Priority::Low,
+    );
+    assert!(matches!(cache.get(&key(0)), Some(Block::Data(block)) if *block == entries));
+    assert_eq!(cache.stats().secondary_hits, 1);
+    drop(cache);
+
+    // the file survives a restart
+    let secondary = SecondaryCache::open(path, 1000)?;
+    assert!(secondary.get(&key(0)).is_some());
+    drop(secondary);
+
+    // a corrupted block fails the checksum on load
+    let mut bytes = fs::read(path)?;
+    let last = bytes.len() - 1;
+    bytes[last] ^= 0xff;
+    fs::write(path, bytes)?;
+
+    let secondary = SecondaryCache::open(path, 1000)?;
+    assert!(secondary.get(&key(0)).is_none());
+
+    let _ = fs::remove_file(path);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_range_filter() {
+    let filter = RangeFilter::from_keys(&["apple", "apricot", "banana"], 8, 0.01);
+
+    assert!(filter.may_contain_range("ap", "aq"));
+    assert!(filter.may_contain_range("a", "c"));
+    assert!(filter.may_contain_range("ban", "bao"));
+    assert!(!filter.may_contain_range("x", "z"));
+    assert!(!filter.may_contain_range("apples", "applet"));
+    assert!(!filter.may_contain_range("b", "a"));
+
+    // tables add their keys one at a time to a filter sized up front
+    let mut filter = RangeFilter::new(3, 8, 0.01);
+    ["apple", "apricot", "banana"]
+        .iter()
+        .for_each(|key| filter.add(key));
+
+    assert!(filter.may_contain_range("ap", "aq"));
+    assert!(filter.may_contain_range("ban", "bao"));
+    assert!(!filter.may_contain_range("x", "z"));
+    assert!(!filter.may_contain_range("apples", "applet"));
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_tombstone_shadows_older_table() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..11 {
+        let _ = lsm.add(&format!("{:02}", i), "old");
+    }
+    let _ = lsm.delete("03");
+    for i in 11..20 {
+        let _ = lsm.add(&format!("{:02}", i), "new");
+    }
+    let _ = lsm.add("20", "new");
+
+    lsm.wait_for_background_work().await?;
+
+    let lsm = Lsm::default();
+
+    assert_eq!(lsm.get("02")?, Some("old".to_owned()));
+    assert_eq!(lsm.get("03")?, None);
+    assert_eq!(lsm.get("15")?, Some("new".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_leveled_compaction() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+    let value = "v".repeat(40);
+
+    // the third flush fills L0 up to its limit and wakes the compaction
+    for i in 0..31 {
+        let _ = lsm.add(&format!("{:02}", i), &value);
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    let tables = lsm
+        .table_properties()?
+        .into_iter()
+        .map(|(table, _)| table)
+        .collect::<Vec<_>>();
+
+    assert!(tables.iter().all(|t| t.level > 0));
+    assert!(tables.len() > 1);
+    assert!(tables.iter().all(|t| t.file_size > 0));
+
+    for a in tables.iter() {
+        for b in tables.iter().filter(|b| b.level == a.level && b.id != a.id) {
+            assert!(!a.overlaps(&b.min_key, &b.max_key));
+        }
+    }
+
+    for i in 0..31 {
+        assert_eq!(lsm.get(&format!("{:02}", i))?, Some(value.clone()));
+    }
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_bottommost_compaction_drops_tombstones() -> Result<(), Box<dyn std::error::Error>>
+{
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..20 {
+        let _ = lsm.add(&format!("{:02}", i), "old");
+    }
+    for i in 0..10 {
+        let _ = lsm.delete(&format!("{:02}", i));
+    }
+    for i in 10..21 {
+        let _ = lsm.add(&format!("{:02}", i), "new");
+    }
+
+    // the third flush wakes the compaction, L1 is the bottommost level
+    lsm.wait_for_background_work().await?;
+
+    let stats = lsm.compaction_stats();
+    assert_eq!(stats.compactions, 1);
+    assert!(stats.dropped_tombstones > 0);
+    assert!(stats.dropped_versions > 0);
+    assert!(stats.reclaimed_bytes > 0);
+    assert_eq!(
+        stats.input_bytes - stats.output_bytes,
+        stats.reclaimed_bytes
+    );
+
+    let tables = lsm.table_properties()?;
+    assert!(tables.iter().all(|(table, _)| table.level == 1));
+    assert!(tables.iter().all(|(_, p)| p.tombstone_count == 0));
+
+    assert_eq!(lsm.get("05")?, None);
+    assert_eq!(lsm.get("15")?, Some("new".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_compact_range() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..20 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+    for i in 0..5 {
+        let _ = lsm.delete(&format!("{:02}", i));
+    }
+    let _ = lsm.add("20", "value");
+
+    lsm.wait_for_background_work().await?;
+
+    // two L0 tables stay below the limit of the background compaction
+    assert_eq!(lsm.compaction_stats().compactions, 0);
+
+    lsm.compact_range("00", "04", 1)?;
+
+    let stats = lsm.compaction_stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.dropped_tombstones, 5);
+    assert_eq!(stats.dropped_versions, 5);
+
+    let tables = lsm.table_properties()?;
+    assert!(!tables.is_empty());
+    assert!(tables.iter().all(|(table, _)| table.level == 1));
+
+    assert_eq!(lsm.get("02")?, None);
+    assert_eq!(lsm.get("07")?, Some("value".to_owned()));
+    assert_eq!(lsm.get("15")?, Some("value".to_owned()));
+
+    // nothing overlaps the range, L0 and beyond the last level are errors
+    lsm.compact_range("50", "60", 1)?;
+    assert_eq!(lsm.compaction_stats().compactions, 1);
+    assert!(lsm.compact_range("00", "99", 0).is_err());
+    assert!(
+        lsm.compact_range("00", "99", config.ss_table.max_levels)
+            .is_err()
+    );
+    assert_eq!(lsm.compaction_stats().compactions, 1);
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_subcompactions() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+    let value = "v".repeat(40);
+
+    for i in 0..21 {
+        let _ = lsm.add(&format!("{:02}", i), &value);
+    }
+    let _ = lsm.delete("15");
+
+    lsm.wait_for_background_work().await?;
+
+    // two L0 tables of ten entries each are split at their smallest keys
+    lsm.compact_range("00", "99", 1)?;
+
+    let stats = lsm.compaction_stats();
+    assert_eq!(stats.compactions, 1);
+    assert_eq!(stats.subcompactions, 2);
+    assert!(stats.input_bytes > 2 * config.ss_table.target_file_size);
+
+    let tables = lsm
+        .table_properties()?
+        .into_iter()
+        .map(|(table, _)| table)
+        .collect::<Vec<_>>();
+
+    assert!(tables.iter().all(|t| t.level == 1));
+    for a in tables.iter() {
+        for b in tables.iter().filter(|b| b.id != a.id) {
+            assert!(!a.overlaps(&b.min_key, &b.max_key));
+        }
+    }
+
+    for i in (0..21).filter(|i| *i != 15) {
+        assert_eq!(lsm.get(&format!("{:02}", i))?, Some(value.clone()));
+    }
+    assert_eq!(lsm.get("15")?, None);
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_trivial_move() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    // sequential keys give L0 tables which don't overlap
+    for i in 0..31 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    let stats = lsm.compaction_stats();
+    assert_eq!(stats.trivial_moves, 3);
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.output_bytes, 0);
+
+    let tables = lsm.table_properties()?;
+    assert_eq!(tables.len(), 3);
+    assert!(tables.iter().all(|(table, _)| table.level == 1));
+    assert!(
+        tables
+            .iter()
+            .all(|(table, _)| table.path.ends_with(&format!("L1_{:010}.sst", table.id)))
+    );
+    assert_eq!(fs::read_dir(&config.directory.ss_table)?.count(), 3);
+
+    for i in 0..31 {
+        assert_eq!(lsm.get(&format!("{:02}", i))?, Some("value".to_owned()));
+    }
+
+    // overlapping tables have to be merged
+    let overlapping = CompactionJob {
+        level: 0,
+        inputs: tables
+            .iter()
+            .map(|(table, _)| SSTableBasicInfo {
+                level: 0,
+                max_key: "99".to_owned(),
+                ..table.clone()
+            })
+            .collect(),
+        output_level: 1,
+    };
+    assert!(!overlapping.is_trivial_move(&Manifest::read_from_file()?));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_rate_limiter() {
+    let limiter = Arc::new(RateLimiter::new(100_000));
+
+    // a full bucket is granted right away, the debt of the next request is waited out after it
+    let start = Instant::now();
+    limiter.request(100_000, IoPriority::Low);
+    assert!(start.elapsed() < Duration::from_millis(100));
+    limiter.request(50_000, IoPriority::Low);
+    limiter.request(1, IoPriority::Low);
+    assert!(start.elapsed() >= Duration::from_millis(400));
+
+    limiter.set_bytes_per_second(0);
+    let start = Instant::now();
+    limiter.request(u32::MAX as u64, IoPriority::Low);
+    assert!(start.elapsed() < Duration::from_millis(100));
+
+    // a waiting low request yields to a high one which came later
+    limiter.set_bytes_per_second(10_000);
+    limiter.request(20_000, IoPriority::High);
+
+    let low = {
+        let limiter = Arc::clone(&limiter);
+        thread::spawn(move || {
+            limiter.request(1, IoPriority::Low);
+            Instant::now()
+        })
+    };
+    thread::sleep(Duration::from_millis(50));
+    limiter.request(1, IoPriority::High);
+    let high_done = Instant::now();
+
+    assert!(low.join().unwrap() >= high_done);
+
+    // every database limits its own background I/O
+    let first = Cache::new();
+    let second = Cache::new();
+    first.rate_limiter().set_bytes_per_second(1);
+    assert_eq!(first.rate_limiter().bytes_per_second(), 1);
+    assert_eq!(second.rate_limiter().bytes_per_second(), 0);
+}
+
+#[derive(Debug)]
+struct UpgradeFilter {}
+
+impl CompactionFilter for UpgradeFilter {
+    fn name(&self) -> &'static str {
+        "upgrade"
+    }
+
+    fn filter(&self, _level: usize, key: &str, value: &str) -> FilterDecision {
+        if key.starts_with("drop") {
+            return FilterDecision::Remove;
+        }
+
+        match value.strip_prefix("v1:") {
+            Some(rest) => FilterDecision::ChangeValue(format!("v2:{}", rest)),
+            None => FilterDecision::Keep,
+        }
+    }
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_compaction_filter() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::with_compaction_filter(Box::new(UpgradeFilter {}));
+
+    for i in 0..5 {
+        let _ = lsm.add(&format!("a{:02}", i), &format!("v1:{}", i));
+    }
+    for i in 0..5 {
+        let _ = lsm.add(&format!("drop{:02}", i), "value");
+    }
+    // keeps the other keys out of the immutable memtable, two L0 tables stay below the limit
+    for i in 0..10 {
+        let _ = lsm.add(&format!("m{:02}", i), "keep");
+    }
+    let _ = lsm.add("z", "keep");
+
+    lsm.wait_for_background_work().await?;
+
+    // cached rows must not outlive the filter
+    assert_eq!(lsm.get("drop03")?, Some("value".to_owned()));
+    assert_eq!(lsm.get("a04")?, Some("v1:4".to_owned()));
+
+    lsm.compact_range("a", "z", 1)?;
+
+    let stats = lsm.compaction_stats();
+    assert_eq!(stats.removed_by_filter, 5);
+    assert_eq!(stats.changed_by_filter, 5);
+    assert_eq!(stats.dropped_tombstones, 5);
+
+    assert_eq!(lsm.get("drop03")?, None);
+    assert_eq!(lsm.get("a04")?, Some("v2:4".to_owned()));
+    assert_eq!(lsm.get("m05")?, Some("keep".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_failed_compaction() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    // the filter reads every blob value, the blobs sort after the inline values
+    let mut lsm = Lsm::with_compaction_filter(Box::new(UpgradeFilter {}));
+    let large_value = "x".repeat(config.blob.value_size_threshold + 1);
+    let inline_value = "x".repeat(config.blob.value_size_threshold - 4);
+
+    for i in 0..5 {
+        lsm.add(&format!("z{:02}", i), &large_value)?;
+    }
+    for i in 0..40 {
+        lsm.add(&format!("a{:02}", i), &inline_value)?;
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    let ids = |lsm: &Lsm| -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+        let mut ids = lsm
+            .table_properties()?
+            .iter()
+            .map(|(t, _)| t.id)
+            .collect::<Vec<_>>();
+        ids.sort();
+        Ok(ids)
+    };
+    let before = ids(&lsm)?;
+
+    // outputs written before the failure are deleted, the inputs stay live
+    let moved = format!("{}_moved", config.directory.blob);
+    fs::rename(&config.directory.blob, &moved)?;
+    assert!(lsm.compact_range("a", "zz", 2).is_err());
+    fs::rename(&moved, &config.directory.blob)?;
+
+    assert_eq!(ids(&lsm)?, before);
+    assert_eq!(
+        fs::read_dir(&config.directory.ss_table)?.count(),
+        before.len()
+    );
+    assert_eq!(lsm.get("a07")?, Some(inline_value.clone()));
+    assert_eq!(lsm.get("z03")?, Some(large_value.clone()));
+
+    lsm.compact_range("a", "zz", 2)?;
+    assert_eq!(lsm.get("a07")?, Some(inline_value));
+    assert_eq!(lsm.get("z03")?, Some(large_value));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct LargeValueFilter {
+    armed: Arc<AtomicBool>,
+    value: String,
+}
+
+impl CompactionFilter for LargeValueFilter {
+    fn name(&self) -> &'static str {
+        "large_value"
+    }
+
+    fn filter(&self, _level: usize, key: &str, _value: &str) -> FilterDecision {
+        if !self.armed.load(Ordering::Relaxed) || !key.starts_with('a') {
+            return FilterDecision::Keep;
+        }
+
+        FilterDecision::ChangeValue(self.value.clone())
+    }
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_failed_compaction_blob_files() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let armed = Arc::new(AtomicBool::new(false));
+    let large_value = "x".repeat(config.blob.value_size_threshold + 1);
+    let changed_value = "y".repeat(config.blob.value_size_threshold + 1);
+    let mut lsm = Lsm::with_compaction_filter(Box::new(LargeValueFilter {
+        armed: Arc::clone(&armed),
+        value: changed_value.clone(),
+    }));
+
+    // the z values are the only ones in a blob file
+    for i in 0..30 {
+        lsm.add(&format!("a{:02}", i), "small")?;
+    }
+    for i in 0..5 {
+        lsm.add(&format!("z{:02}", i), &large_value)?;
+    }
+    for i in 0..6 {
+        lsm.add(&format!("m{:02}", i), "small")?;
+    }
+
+    lsm.wait_for_background_work().await?;
+
+    let blob_files = fs::read_dir(&config.directory.blob)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    assert_eq!(blob_files.len(), 1);
+
+    // the changed a values go to new blob files before reading the z values fails
+    armed.store(true, Ordering::Relaxed);
+    let moved = format!("{}_moved", blob_files[0].display());
+    fs::rename(&blob_files[0], &moved)?;
+    assert!(lsm.compact_range("a", "zz", 2).is_err());
+    fs::rename(&moved, &blob_files[0])?;
+
+    assert_eq!(fs::read_dir(&config.directory.blob)?.count(), 1);
+    assert_eq!(lsm.get("a07")?, Some("small".to_owned()));
+
+    lsm.compact_range("a", "zz", 2)?;
+    assert_eq!(lsm.get("a07")?, Some(changed_value));
+    assert_eq!(lsm.get("z03")?, Some(large_value));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct PanickingFilter {
+    armed: Arc<AtomicBool>,
+}
+
+impl CompactionFilter for PanickingFilter {
+    fn name(&self) -> &'static str {
+        "panicking"
+    }
+
+    fn filter(&self, _level: usize, _key: &str, _value: &str) -> FilterDecision {
+        assert!(!self.armed.load(Ordering::Relaxed), "filter armed");
+
+        FilterDecision::Keep
+    }
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_panicking_compaction() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    // a panicking task doesn't take its thread down
+    let pool = ThreadPool::new("test", 1);
+    let (sender, receiver) = mpsc::channel();
+    pool.execute(|| panic!("task panicked"));
+    pool.execute(move || sender.send(()).unwrap());
+    assert!(receiver.recv_timeout(Duration::from_secs(10)).is_ok());
+
+    let armed = Arc::new(AtomicBool::new(true));
+    let mut lsm = Lsm::with_compaction_filter(Box::new(PanickingFilter {
+        armed: Arc::clone(&armed),
+    }));
+
+    for i in 0..=config.memtable.max_entries {
+        lsm.add(&format!("{:02}", i), "value")?;
+    }
+    lsm.wait_for_background_work().await?;
+
+    // a single table is merged on the calling thread, the panic reaches the caller
+    let result = panic::catch_unwind(AssertUnwindSafe(|| lsm.compact_range("00", "99", 1)));
+    assert!(result.is_err());
+
+    // the compaction was taken off the running ones, nothing waits for it
+    armed.store(false, Ordering::Relaxed);
+    lsm.wait_for_background_work().await?;
+    lsm.compact_range("00", "99", 1)?;
+
+    assert_eq!(lsm.compaction_stats().compactions, 1);
+    assert_eq!(lsm.get("05")?, Some("value".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[test]
+#[serial_test::serial]
+pub fn test_compaction_strategies() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let table = |id: usize, level: usize| {
+        SSTableBasicInfo::new(
+            id,
+            format!("L{}_{:010}.sst", level, id),
+            level,
+            format!("{:02}", id),
+            format!("{:02}", id + 10),
+            100,
+            0,
+        )
+    };
+
+    let mut manifest = Manifest::new();
+    assert_eq!(manifest.compaction_style(), LEVELED);
+
+    let size_tiered = compaction_strategy::from_name(SIZE_TIERED)?;
+    let leveled = compaction_strategy::from_name(LEVELED)?;
+
+    for id in [3, 1, 2] {
+        manifest.add(table(id, 0));
+    }
+    assert!(size_tiered.pick(&mut manifest, &BTreeSet::new()).is_none());
+
+    let job = leveled.pick(&mut manifest, &BTreeSet::new()).unwrap();
+    assert_eq!((job.level, job.output_level), (0, 1));
+    assert_eq!(
+        job.inputs.iter().map(|t| t.id).collect::<Vec<_>>(),
+        vec![1, 2, 3]
+    );
+
+    // tables of running compactions are never picked again
+    let job = leveled.pick(&mut manifest, &BTreeSet::from([2])).unwrap();
+    assert_eq!(
+        job.inputs.iter().map(|t| t.id).collect::<Vec<_>>(),
+        vec![1, 3]
+    );
+    assert!(
+        leveled
+            .pick(&mut manifest, &BTreeSet::from([1, 2, 3]))
+            .is_none()
+    );
+
+    // a full tier is merged into the next one, the last tier into itself
+    manifest.add(table(4, 0));
+    let job = size_tiered.pick(&mut manifest, &BTreeSet::new()).unwrap();
+    assert_eq!((job.level, job.output_level), (0, 1));
+    assert_eq!(job.inputs.len(), 4);
+    assert!(
+        size_tiered
+            .pick(&mut manifest, &BTreeSet::from([4]))
+            .is_none()
+    );
+
+    let mut manifest = Manifest::default();
+    for id in 5..9 {
+        manifest.add(table(id, config.ss_table.max_levels - 1));
+    }
+    let job = size_tiered.pick(&mut manifest, &BTreeSet::new()).unwrap();
+    assert_eq!(job.output_level, config.ss_table.max_levels - 1);
+    assert_eq!(size_tiered.target_file_size(), u64::MAX);
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_size_tiered_flush_racing_merge() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+    let last_tier = config.ss_table.max_levels - 1;
+
+    // the flush holding the new value got its id before the merge of the last tier got its own
+    write_tables(
+        SIZE_TIERED,
+        0,
+        vec![
+            vec![("a", Some("1"))],
+            vec![("c", Some("1"))],
+            vec![("e", Some("1"))],
+            vec![("k", Some("new"))],
+        ],
+    )?;
+    write_tables(
+        SIZE_TIERED,
+        last_tier,
+        vec![vec![("j", Some("old")), ("k", Some("old"))]],
+    )?;
+
+    // the tables move down the empty tiers, the last tier holds a newer id overlapping them
+    let mut lsm = Lsm::default();
+    lsm.wait_for_background_work().await?;
+
+    assert_eq!(lsm.get("k")?, Some("new".to_owned()));
+    assert_eq!(lsm.get("j")?, Some("old".to_owned()));
+
+    // the move into the last tier becomes a merge
+    let stats = lsm.compaction_stats();
+    assert_eq!(stats.trivial_moves, 4 * (last_tier as u64 - 1));
+    assert_eq!(stats.compactions, 1);
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_size_tiered_last_tier() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+    let last_tier = config.ss_table.max_levels - 1;
+
+    let check =
+        |lsm: &Lsm, expected: &[(&str, Option<&str>)]| -> Result<(), Box<dyn std::error::Error>> {
+            for (key, value) in expected {
+                assert_eq!(lsm.get(key)?, value.map(|v| v.to_owned()), "key {}", key);
+            }
+
+            let tables = lsm.table_properties()?;
+            assert_eq!(tables.len(), 1);
+            assert_eq!(tables[0].0.level, last_tier);
+
+            Ok(())
+        };
+
+    // a full last tier merges into itself on open
+    write_tables(
+        SIZE_TIERED,
+        last_tier,
+        vec![
+            vec![
+                ("a", Some("1")),
+                ("b", Some("1")),
+                ("c", Some("1")),
+                ("d", Some("1")),
+            ],
+            vec![("a", Some("2")), ("b", Some("2"))],
+            vec![("b", None), ("e", Some("3"))],
+            vec![("c", None), ("f", Some("4"))],
+        ],
+    )?;
+
+    let mut lsm = Lsm::default();
+    lsm.wait_for_background_work().await?;
+    assert_eq!(lsm.compaction_stats().compactions, 1);
+    check(
+        &lsm,
+        &[
+            ("a", Some("2")),
+            ("b", None),
+            ("c", None),
+            ("d", Some("1")),
+            ("e", Some("3")),
+            ("f", Some("4")),
+        ],
+    )?;
+    drop(lsm);
+
+    // the merged table is the oldest of the next round
+    write_tables(
+        SIZE_TIERED,
+        last_tier,
+        vec![
+            vec![("a", None), ("g", Some("5"))],
+            vec![("b", Some("6")), ("d", Some("6"))],
+            vec![("d", None), ("e", Some("7"))],
+        ],
+    )?;
+
+    let mut lsm = Lsm::default();
+    lsm.wait_for_background_work().await?;
+    assert_eq!(lsm.compaction_stats().compactions, 1);
+    check(
+        &lsm,
+        &[
+            ("a", None),
+            ("b", Some("6")),
+            ("c", None),
+            ("d", None),
+            ("e", Some("7")),
+            ("f", Some("4")),
+            ("g", Some("5")),
+        ],
+    )?;
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[test]
+#[serial_test::serial]
+pub fn test_compaction_scores() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let table = |id: usize, level: usize, file_size: u64| {
+        SSTableBasicInfo::new(
+            id,
+            format!("L{}_{:010}.sst", level, id),
+            level,
+            format!("{:02}", id * 10),
+            format!("{:02}", id * 10 + 5),
+            file_size,
+            0,
+        )
+    };
+
+    let leveled = compaction_strategy::from_name(LEVELED)?;
+    let mut manifest = Manifest::new();
+
+    for id in 1..4 {
+        manifest.add(table(id, 0, 100));
+    }
+    let base = config.ss_table.max_bytes_for_level_base;
+    manifest.add(table(4, 1, base));
+    manifest.add(table(5, 1, base));
+
+    // L1 holds twice its target, L0 exactly its table count limit
+    let scores = leveled.scores(&manifest);
+    assert_eq!(scores[0].level, 1);
+    assert_eq!(scores[0].score, 2.0);
+    assert_eq!(scores[1].level, 0);
+    assert_eq!(scores[1].score, 1.0);
+
+    let job = leveled.pick(&mut manifest, &BTreeSet::new()).unwrap();
+    assert_eq!((job.level, job.output_level), (1, 2));
+
+    // the next level goes once the highest one is busy
+    let busy = BTreeSet::from([4, 5]);
+    let job = leveled.pick(&mut manifest, &busy).unwrap();
+    assert_eq!(job.level, 0);
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_flush_wakes_compaction() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let mut lsm = Lsm::default();
+
+    for i in 0..31 {
+        let _ = lsm.add(&format!("{:02}", i), "value");
+    }
+
+    // the third flush fills L0 up to its limit
+    lsm.wait_for_background_work().await?;
+
+    let stats = lsm.compaction_stats();
+    assert!(stats.compactions + stats.trivial_moves > 0);
+    assert!(lsm.compaction_scores().iter().all(|s| s.score < 1.0));
+    assert_eq!(lsm.get("05")?, Some("value".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[test]
+#[serial_test::serial]
+pub fn test_fifo_compaction() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let mut manifest = Manifest::default();
+    for (id, creation_time) in [(2, now - 120), (1, now - 120), (3, now), (4, now)] {
+        manifest.add(SSTableBasicInfo::new(
+            id,
+            format!("L0_{:010}.sst", id),
+            0,
+            "a".to_owned(),
+            "z".to_owned(),
+            100,
+            creation_time,
+        ));
+    }
+
+    let ids = |tables: Vec<SSTableBasicInfo>| tables.iter().map(|t| t.id).collect::<Vec<_>>();
+
+    // the oldest tables go first until the rest fits
+    let by_size = FifoCompaction {
+        max_size: 250,
+        ttl_seconds: 0,
+    };
+    assert_eq!(ids(by_size.expired(&manifest)), vec![1, 2]);
+    assert!(by_size.pick(&mut manifest, &BTreeSet::new()).is_none());
+
+    let by_age = FifoCompaction {
+        max_size: 0,
+        ttl_seconds: 60,
+    };
+    assert_eq!(ids(by_age.expired(&manifest)), vec![1, 2]);
+
+    let unlimited = FifoCompaction {
+        max_size: 0,
+        ttl_seconds: 0,
+    };
+    assert!(unlimited.expired(&manifest).is_empty());
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[serial_test::serial]
+pub async fn test_fifo_ttl() -> Result<(), Box<dyn std::error::Error>> {
+    let config = set_up();
+
+    write_tables(FIFO, 0, vec![vec![("a", Some("1")), ("b", Some("2"))]])?;
+
+    let mut lsm = Lsm::default();
+    lsm.wait_for_background_work().await?;
+    assert_eq!(lsm.get("a")?, Some("1".to_owned()));
+
+    // the next flush drops the table once it is older than fifo_ttl_seconds
+    tokio::time::sleep(Duration::from_millis(
+        config.ss_table.fifo_ttl_seconds * 1000 + 1500,
+    ))
+    .await;
+    for i in 0..=config.memtable.max_entries {
+        lsm.add(&format!("k{:02}", i), "value")?;
+    }
+    lsm.wait_for_background_work().await?;
+
+    assert_eq!(lsm.compaction_stats().dropped_tables, 1);
+    assert_eq!(lsm.get("a")?, None);
+    assert_eq!(lsm.get("b")?, None);
+    assert_eq!(lsm.get("k00")?, Some("value".to_owned()));
+
+    tear_down(config);
+
+    Ok(())
+}
+
+#[test]
+pub fn test_merge_iterator() -> Result<(), Box<dyn std::error::Error>> {
+    let inline = |value: &str| Some(SSTableValue::Inline(value.to_owned()));
+    let input = |entries: Vec<(&str, Option<SSTableValue>)>| {
+        entries
+            .into_iter()
+            .map(|(key, value)| Ok((key.to_owned(), value)))
+            .collect::<Vec<_>>()
+            .into_iter()
+    };
+
+    // from the oldest to the newest input
+    let inputs = vec![
+        input(vec![
+            ("a", inline("1")),
+            ("c", inline("1")),
+            ("e", inline("1")),
+        ]),
+        input(vec![("b", inline("2")), ("c", inline("2"))]),
+        input(vec![("c", None), ("d", inline("3")), ("e", inline("3"))]),
+    ];
+
+    let merged = MergeIterator::new(inputs).collect::<Result<Vec<_>, _>>()?;
+
+    let keys = merged.iter().map(|e| e.key.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, vec!["a", "b", "c", "d", "e"]);
+
+    let c = &merged[2];
+    assert_eq!(c.value, None);
+    assert_eq!(c.shadowed.len(), 2);
+    assert!(c.shadowed.contains(&inline("1")) && c.shadowed.contains(&inline("2")));
+
+    assert_eq!(merged[4].value, inline("3"));
+    assert_eq!(merged[4].shadowed, vec![inline("1")]);
+
+    // a failing input ends the merge with its error
+    let failing = vec![
+        input(vec![("a", inline("1"))]),
+        vec![Err(LsmError::SsTable("broken".to_owned()))].into_iter(),
+    ];
+    assert!(MergeIterator::new(failing).any(|e| e.is_err()));
+
+    Ok(())
+}
+
+#[test]
+pub fn test_bloom_filter() -> Result<(), Box<dyn std::error::Error>> {
+    let keys = (0..1000)
+        .map(|i| format!("key{}", i))
+        .collect::<Vec<String>>();
+    let keys = keys.iter().map(|k| k.as_str()).collect::<Vec<&str>>();
+
+    let bloom_filter = BloomFilter::from_keys(&keys, 0.01);
+    assert!(keys.iter().all(|k| bloom_filter.contains(k)));
+
+    let false_positives = (0..10000)
+        .filter(|i| bloom_filter.contains(&format!("other{}", i)))
+        .count();
+    assert!(false_positives < 300);
+
+    // legacy filters stored one usize per bit and probed with the seeds 1 to 3
+    let size = 50;
+    let mut legacy = vec![0usize; size];
+    for seed in 1..=3 {
+        legacy[xxhash_rust::xxh3::xxh3_64_with_seed(b"legacy", seed) as usize % size] = 1;
+    }
+
+    let bloom_filter = BloomFilter::decode(&bincode::serialize(&(legacy, size))?, true)?;
+    assert!(bloom_filter.contains("legacy"));
+
+    Ok(())
+}
+
+#[test]
+pub fn test_filter_policies() -> Result<(), Box<dyn std::error::Error>> {
+    set_up();
+
+    let keys = (0..1000)
+        .map(|i| format!("key{}", i))
+        .collect::<Vec<String>>();
+    let keys = keys.iter().map(|k| k.as_str()).collect::<Vec<&str>>();
+
+    for name in [
+        filter_policy::BLOOM,
+        filter_policy::BLOCKED_BLOOM,
+        filter_policy::XOR,
+    ] {
+        let policy = filter_policy::from_name(name)?;
+        let filter = policy.decode(&policy.build(&keys)?)?;
+
+        assert!(keys.iter().all(|k| filter.contains(k)));
+
+        let false_positives = (0..10000)
+            .filter(|i| filter.contains(&format!("other{}", i)))
+            .count();
+        assert!(
+            false_positives < 300,
+            "{} false positives for {}",
+            false_positives,
+            name
+        );
+    }
+
+    // legacy bloom filters are only read
+    assert!(filter_policy::for_writing(filter_policy::LEGACY_BLOOM).is_err());
+    assert!(
+        filter_policy::from_name(filter_policy::LEGACY_BLOOM)?
+            .build(&keys)
+            .is_err()
+    );
+
+    Ok(())
+}
+
+#[test]
+pub fn test_config_defaults() -> Result<(), Box<dyn std::error::Error>> {
+    // a configuration written before any of the newer keys existed still loads
+    let config = Config::parse(
+        r#"
+        [wal]
+        version = 1
+
+        [memtable]
+        max_entries = 5
+
+        [directory]
+        wal = "data/wals"
+        ss_table = "data/ss_tables"
+        log = "log/config/log4rs.yaml"
+
+        [cache]
+        index_size = 5
+        bloom_filter_size = 50
+
+        [ss_table]
+        manifest_location = "./manifest.json"
+        l0_file_count_limit = 3
+        l1_file_size_upper_limit = 1000
+        "#,
+    )?;
+    assert_eq!(config.ss_table.compaction_style, LEVELED);
+    assert_eq!(config.cache.filter_policy, filter_policy::BLOOM);
+    assert_eq!(config.directory.blob, "data/blobs");
+    assert_eq!(config.io.rate_limit_bytes_per_second, 0);
+
+    // unknown names fail on load instead of on the first flush or compaction
+    let content = fs::read_to_string("config.test.toml")?;
+    assert!(Config::parse(&content).is_ok());
+    assert!(
+        Config::parse(&content.replace(
+            "compaction_style = \"leveled\"",
+            "compaction_style = \"tiered\""
+        ))
+        .is_err()
+    );
+    assert!(
+        Config::parse(&content.replace(
+            "filter_policy = \"xor\"",
+            "filter_policy = \"legacy_bloom\""
+        ))
+        .is_err()
+    );
+
+    Ok(())
+}
+
+fn set_up() -> &'static Config {
+    let config = Config::test();
+
+    let _ = fs::create_dir_all(&config.directory.wal);
+    let _ = fs::create_dir_all(&config.directory.ss_table);
+    let _ = fs::create_dir_all(&config.directory.blob);
+
+    config
+}
+
+fn tear_down(config: &Config) {
+    let _ = fs::remove_dir_all(&config.directory.wal);
+    let _ = fs::remove_dir_all(&config.directory.ss_table);
+    let _ = fs::remove_dir_all(&config.directory.blob);
+    let _ = fs::remove_file(&config.ss_table.manifest_location);
+    let _ = fs::remove_file(format!("{}.tmp", config.ss_table.manifest_location));
+    let _ = fs::remove_file(&config.cache.secondary_cache_path);
+}
+
+/*
+ * Writes the tables straight into the level, oldest first. A new manifest
+ * gets the compaction style, it is kept since the manifest holds tables.
+ */
+fn write_tables(
+    compaction_style: &str,
+    level: usize,
+    tables: Vec<Vec<(&str, Option<&str>)>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let manifest = match Manifest::read_from_file() {
+        Ok(manifest) => manifest,
+        Err(_) => serde_json::from_value(serde_json::json!({
+            "version": 1,
+            "next_id": 1,
+            "ss_tables": [],
+            "compaction_style": compaction_style,
+        }))?,
+    };
+    let manifest = Arc::new(RwLock::new(manifest));
+    let cache = Arc::new(RwLock::new(Cache::new()));
+
+    for entries in tables {
+        let entries = entries
+            .into_iter()
+            .map(|(key, value)| {
+                (
+                    key.to_owned(),
+                    value.map(|v| SSTableValue::Inline(v.to_owned())),
+                )
+            })
+            .collect();
+        SSTableManager::write_table(
+            entries,
+            (0, 0),
+            Arc::clone(&cache),
+            Arc::clone(&manifest),
+            level,
+            IoPriority::High,
+        )?;
+    }
+
+    Ok(())
 }